@@ -16,6 +16,13 @@ struct RakalyCommand {
 enum GameCommand {
     Melt(crate::melt::MeltCommand),
     Json(crate::json::JsonCommand),
+    Text(crate::text::TextCommand),
+    Interpolate(crate::interpolate::InterpolateCommand),
+    Table(crate::table::TableCommand),
+    Sqlite(crate::sqlite::SqliteCommand),
+    Parquet(crate::columnar::ParquetCommand),
+    Schema(crate::schema::SchemaCommand),
+    Vars(crate::vars::VarsCommand),
     Watch(crate::watch::WatchCommand),
 }
 
@@ -28,6 +35,13 @@ pub fn run() -> anyhow::Result<i32> {
         match cmd {
             GameCommand::Melt(melt) => melt.exec(),
             GameCommand::Json(json) => json.exec(),
+            GameCommand::Text(text) => text.exec(),
+            GameCommand::Interpolate(interpolate) => interpolate.exec(),
+            GameCommand::Table(table) => table.exec(),
+            GameCommand::Sqlite(sqlite) => sqlite.exec(),
+            GameCommand::Parquet(parquet) => parquet.exec(),
+            GameCommand::Schema(schema) => schema.exec(),
+            GameCommand::Vars(vars) => vars.exec(),
             GameCommand::Watch(watch) => watch.exec(),
         }
     } else {
@@ -0,0 +1,444 @@
+use anyhow::Context;
+use argh::FromArgs;
+use arrow::{
+    array::{
+        ArrayRef, BooleanBuilder, Date32Builder, Float64Builder, Int64Builder, ListBuilder,
+        StringBuilder,
+    },
+    datatypes::{DataType, Field, Schema},
+    ipc::writer::FileWriter,
+    record_batch::RecordBatch,
+};
+use jomini::json::{DuplicateKeyMode, JsonOptions};
+use parquet::arrow::ArrowWriter;
+use std::{fs::File, path::PathBuf, sync::Arc};
+
+use crate::{
+    json::{parse_input_encoding, JsonSource},
+    table::{cell, collection_rows, KEY_COLUMN},
+    value::Value,
+};
+
+/// export a collection of entities (eg: provinces) as parquet or arrow ipc
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "parquet")]
+pub(crate) struct ParquetCommand {
+    /// dotted path to the collection of entities, eg: provinces
+    #[argh(option, short = 'p')]
+    path: String,
+
+    /// comma separated columns with nested fields separated by dots (eg:
+    /// owner,history.owner). Omission includes every field
+    #[argh(option, short = 'c')]
+    columns: Option<String>,
+
+    /// output file. Files ending in .arrow, .ipc, or .feather are written as
+    /// arrow ipc, otherwise parquet
+    #[argh(option, short = 'o')]
+    out: PathBuf,
+
+    /// specify the format of generic (non-save) files: auto | utf-8 | windows-1252
+    #[argh(option, short = 'f', default = "String::from(\"auto\")")]
+    format: String,
+
+    /// save or game file to read
+    #[argh(positional)]
+    file: PathBuf,
+}
+
+impl ParquetCommand {
+    pub(crate) fn exec(&self) -> anyhow::Result<i32> {
+        let data = std::fs::read(&self.file)
+            .with_context(|| format!("Failed to read: {}", self.file.display()))?;
+        let source = JsonSource {
+            file: &self.file,
+            encoding: parse_input_encoding(&self.format)?,
+            interpolation: false,
+            with_meta: false,
+            strip_formatting: false,
+            preserve_operators: false,
+            variables: None,
+            params: None,
+            mod_root: None,
+        };
+
+        let options = JsonOptions::new().with_duplicate_keys(DuplicateKeyMode::Group);
+        let document = source.to_value(&data, options)?;
+        let collection = document
+            .lookup(&self.path)
+            .with_context(|| format!("Path not found: {}", self.path))?;
+        let rows = collection_rows(collection)?;
+
+        let columns: Vec<String> = match self.columns.as_deref() {
+            Some(columns) => columns.split(',').map(|x| x.trim().to_owned()).collect(),
+            None => field_names(&rows),
+        };
+
+        let batch = record_batch(&rows, &columns)?;
+        let out = File::create(&self.out)
+            .with_context(|| format!("Unable to create file: {}", self.out.display()))?;
+
+        let extension = self.out.extension().and_then(|x| x.to_str());
+        if matches!(extension, Some("arrow" | "ipc" | "feather")) {
+            let mut writer = FileWriter::try_new(out, &batch.schema())?;
+            writer.write(&batch)?;
+            writer.finish()?;
+        } else {
+            let mut writer = ArrowWriter::try_new(out, batch.schema(), None)?;
+            writer.write(&batch)?;
+            writer.close()?;
+        }
+
+        Ok(0)
+    }
+}
+
+/// The union of fields across all rows in the order they are first seen
+fn field_names(rows: &[(String, &Value)]) -> Vec<String> {
+    let mut columns: Vec<String> = Vec::new();
+    for (_, row) in rows {
+        for (key, _) in row.as_object().unwrap_or_default() {
+            if !columns.contains(key) {
+                columns.push(key.clone());
+            }
+        }
+    }
+    columns
+}
+
+/// The inferred type of a column
+#[derive(Debug, Clone, PartialEq)]
+enum ColumnType {
+    Int,
+    Float,
+    Bool,
+    Date,
+    String,
+    List(Box<ColumnType>),
+}
+
+impl ColumnType {
+    fn of(value: &Value) -> Option<ColumnType> {
+        match value {
+            Value::Null => None,
+            Value::Bool(_) => Some(ColumnType::Bool),
+            Value::Number(x) if x.is_i64() => Some(ColumnType::Int),
+            Value::Number(_) => Some(ColumnType::Float),
+            Value::String(x) if parse_date(x).is_some() => Some(ColumnType::Date),
+            Value::String(_) => Some(ColumnType::String),
+            Value::Array(values) => {
+                let inner = ColumnType::infer(values.iter());
+                match inner {
+                    // Nested lists are stringified
+                    ColumnType::List(_) => Some(ColumnType::String),
+                    inner => Some(ColumnType::List(Box::new(inner))),
+                }
+            }
+            Value::Object(_) => Some(ColumnType::String),
+        }
+    }
+
+    fn merge(self, other: ColumnType) -> ColumnType {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (ColumnType::Int, ColumnType::Float) | (ColumnType::Float, ColumnType::Int) => {
+                ColumnType::Float
+            }
+            (ColumnType::List(a), ColumnType::List(b)) => ColumnType::List(Box::new(a.merge(*b))),
+            _ => ColumnType::String,
+        }
+    }
+
+    /// Infer the narrowest type that can hold all the given values
+    fn infer<'a>(values: impl Iterator<Item = &'a Value>) -> ColumnType {
+        values
+            .filter_map(ColumnType::of)
+            .reduce(ColumnType::merge)
+            .unwrap_or(ColumnType::String)
+    }
+
+    fn data_type(&self) -> DataType {
+        match self {
+            ColumnType::Int => DataType::Int64,
+            ColumnType::Float => DataType::Float64,
+            ColumnType::Bool => DataType::Boolean,
+            ColumnType::Date => DataType::Date32,
+            ColumnType::String => DataType::Utf8,
+            ColumnType::List(inner) => {
+                DataType::List(Arc::new(Field::new("item", inner.data_type(), true)))
+            }
+        }
+    }
+}
+
+fn record_batch(rows: &[(String, &Value)], columns: &[String]) -> anyhow::Result<RecordBatch> {
+    let mut fields = vec![Field::new(KEY_COLUMN, DataType::Utf8, false)];
+    let mut arrays: Vec<ArrayRef> = Vec::with_capacity(columns.len() + 1);
+
+    let mut ids = StringBuilder::new();
+    for (id, _) in rows {
+        ids.append_value(id);
+    }
+    arrays.push(Arc::new(ids.finish()));
+
+    for column in columns {
+        let values: Vec<Option<&Value>> = rows.iter().map(|(_, row)| row.lookup(column)).collect();
+        let kind = ColumnType::infer(values.iter().flatten().copied());
+        fields.push(Field::new(column, kind.data_type(), true));
+        arrays.push(build_array(&kind, &values));
+    }
+
+    let schema = Arc::new(Schema::new(fields));
+    Ok(RecordBatch::try_new(schema, arrays)?)
+}
+
+fn build_array(kind: &ColumnType, values: &[Option<&Value>]) -> ArrayRef {
+    match kind {
+        ColumnType::Int => {
+            let mut builder = Int64Builder::new();
+            for value in values {
+                builder.append_option(value.and_then(number).and_then(|x| x.as_i64()));
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Float => {
+            let mut builder = Float64Builder::new();
+            for value in values {
+                builder.append_option(value.and_then(number).and_then(|x| x.as_f64()));
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Bool => {
+            let mut builder = BooleanBuilder::new();
+            for value in values {
+                builder.append_option(match value {
+                    Some(Value::Bool(x)) => Some(*x),
+                    _ => None,
+                });
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Date => {
+            let mut builder = Date32Builder::new();
+            for value in values {
+                builder.append_option(match value {
+                    Some(Value::String(x)) => parse_date(x),
+                    _ => None,
+                });
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::String => {
+            let mut builder = StringBuilder::new();
+            for value in values {
+                match value {
+                    None | Some(Value::Null) => builder.append_null(),
+                    Some(x) => builder.append_value(cell(Some(*x))),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::List(inner) => match inner.as_ref() {
+            ColumnType::Int => {
+                let mut builder = ListBuilder::new(Int64Builder::new());
+                for value in values {
+                    match value {
+                        Some(Value::Array(xs)) => {
+                            for x in xs {
+                                let x = number(x).and_then(|x| x.as_i64());
+                                builder.values().append_option(x);
+                            }
+                            builder.append(true);
+                        }
+                        _ => builder.append(false),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnType::Float => {
+                let mut builder = ListBuilder::new(Float64Builder::new());
+                for value in values {
+                    match value {
+                        Some(Value::Array(xs)) => {
+                            for x in xs {
+                                let x = number(x).and_then(|x| x.as_f64());
+                                builder.values().append_option(x);
+                            }
+                            builder.append(true);
+                        }
+                        _ => builder.append(false),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnType::Bool => {
+                let mut builder = ListBuilder::new(BooleanBuilder::new());
+                for value in values {
+                    match value {
+                        Some(Value::Array(xs)) => {
+                            for x in xs {
+                                let x = match x {
+                                    Value::Bool(x) => Some(*x),
+                                    _ => None,
+                                };
+                                builder.values().append_option(x);
+                            }
+                            builder.append(true);
+                        }
+                        _ => builder.append(false),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnType::Date => {
+                let mut builder = ListBuilder::new(Date32Builder::new());
+                for value in values {
+                    match value {
+                        Some(Value::Array(xs)) => {
+                            for x in xs {
+                                let x = match x {
+                                    Value::String(x) => parse_date(x),
+                                    _ => None,
+                                };
+                                builder.values().append_option(x);
+                            }
+                            builder.append(true);
+                        }
+                        _ => builder.append(false),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnType::String | ColumnType::List(_) => {
+                let mut builder = ListBuilder::new(StringBuilder::new());
+                for value in values {
+                    match value {
+                        Some(Value::Array(xs)) => {
+                            for x in xs {
+                                builder.values().append_value(cell(Some(x)));
+                            }
+                            builder.append(true);
+                        }
+                        _ => builder.append(false),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+        },
+    }
+}
+
+fn number(value: &Value) -> Option<&serde_json::Number> {
+    match value {
+        Value::Number(x) => Some(x),
+        _ => None,
+    }
+}
+
+/// Parse a game date (eg: 1444.11.11) into days since the unix epoch.
+/// Version numbers (eg: 1.3.5) share the dotted shape, so the year must have
+/// three or four digits, as every game's start date does. Text that merely
+/// looks like a date (eg: 100.1.1) is still read as one.
+///
+/// The games' calendar has no leap days, so February 29th is rejected, and
+/// the remaining dates keep their day and month in the gregorian calendar
+/// of Date32
+fn parse_date(s: &str) -> Option<i32> {
+    let digits = |x: &str, len: std::ops::RangeInclusive<usize>| {
+        len.contains(&x.len()) && x.bytes().all(|b| b.is_ascii_digit())
+    };
+
+    let mut parts = s.split('.');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some()
+        || !digits(year, 3..=4)
+        || !digits(month, 1..=2)
+        || !digits(day, 1..=2)
+    {
+        return None;
+    }
+
+    let year = year.parse::<i32>().ok()?;
+    let month = month.parse::<u32>().ok()?;
+    let day = day.parse::<u32>().ok()?;
+    let days_in_month = match month {
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+
+    if !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day))
+}
+
+/// Days since 1970-01-01 in the proleptic gregorian calendar
+fn days_from_civil(year: i32, month: u32, day: u32) -> i32 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400) as u32;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe as i32 - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("1970.1.1"), Some(0));
+        assert_eq!(parse_date("2000.3.1"), Some(11017));
+        assert_eq!(parse_date("1444.11.11"), Some(-191803));
+        assert_eq!(parse_date("450.10.1"), Some(-554895));
+        assert_eq!(parse_date("1936.1.1.12"), None);
+        assert_eq!(parse_date("1444.2.28"), Some(-192060));
+        assert_eq!(parse_date("1444.2.29"), None);
+        assert_eq!(parse_date("1444.2.30"), None);
+        assert_eq!(parse_date("2000.2.29"), None);
+        assert_eq!(parse_date("1.3.5"), None);
+        assert_eq!(parse_date("1.37.2"), None);
+        assert_eq!(parse_date("+1444.1.1"), None);
+        assert_eq!(parse_date("Stockholm"), None);
+    }
+
+    #[test]
+    fn test_infer_columns() {
+        let data = r#"{
+            "-1":{"owner":"SWE","base_tax":5,"cores":["SWE"],"founded":"1444.11.11","id":1},
+            "-2":{"owner":"DAN","base_tax":3.5,"cores":["DAN","NOR"],"version":"1.3.5"}
+        }"#;
+        let value = Value::from_slice(data.as_bytes()).unwrap();
+        let rows = collection_rows(&value).unwrap();
+        let columns = field_names(&rows);
+        let batch = record_batch(&rows, &columns).unwrap();
+
+        let schema = batch.schema();
+        let types: Vec<_> = schema
+            .fields()
+            .iter()
+            .map(|x| (x.name().as_str(), x.data_type().clone()))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ("_key", DataType::Utf8),
+                ("owner", DataType::Utf8),
+                ("base_tax", DataType::Float64),
+                (
+                    "cores",
+                    DataType::List(Arc::new(Field::new("item", DataType::Utf8, true)))
+                ),
+                ("founded", DataType::Date32),
+                ("id", DataType::Int64),
+                ("version", DataType::Utf8),
+            ]
+        );
+        assert_eq!(batch.num_rows(), 2);
+    }
+}
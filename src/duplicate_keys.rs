@@ -0,0 +1,208 @@
+use anyhow::{anyhow, Context};
+use jomini::json::DuplicateKeyMode;
+use std::{collections::HashMap, path::Path};
+
+use crate::{json::parse_duplicate_keys, value::Value};
+
+/// Choose how duplicate keys are written per key path, falling back to a
+/// document wide mode.
+///
+/// Paths are dotted keys (eg: `countries.FRA.modifier`) where a `*` matches
+/// any characters within a key and a `**` segment matches any number of keys.
+/// Array elements share the path of the array. The first matching rule wins.
+#[derive(Debug)]
+pub(crate) struct DuplicateKeyRules {
+    rules: Vec<(Vec<String>, DuplicateKeyMode)>,
+    fallback: DuplicateKeyMode,
+}
+
+impl DuplicateKeyRules {
+    pub fn new(fallback: DuplicateKeyMode) -> Self {
+        DuplicateKeyRules {
+            rules: Vec::new(),
+            fallback,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn push(&mut self, pattern: &str, mode: DuplicateKeyMode) {
+        let segments = pattern.split('.').map(String::from).collect();
+        self.rules.push((segments, mode));
+    }
+
+    /// Read rules from a file where each line is a mode followed by a path
+    /// pattern (eg: `group **.history.*`). Blank lines and lines starting
+    /// with `#` are ignored
+    pub fn push_file(&mut self, file: &Path) -> anyhow::Result<()> {
+        let data = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read rules file: {}", file.display()))?;
+
+        for (i, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (mode, pattern) = line.split_once(char::is_whitespace).ok_or_else(|| {
+                anyhow!("{}:{}: expected a mode and a path", file.display(), i + 1)
+            })?;
+            let mode = parse_duplicate_keys(mode)
+                .with_context(|| format!("{}:{}", file.display(), i + 1))?;
+            self.push(pattern.trim(), mode);
+        }
+
+        Ok(())
+    }
+
+    fn mode(&self, path: &[String]) -> DuplicateKeyMode {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| matches_path(pattern, path))
+            .map_or(self.fallback, |(_, mode)| *mode)
+    }
+
+    /// Rewrite a document parsed with `DuplicateKeyMode::Preserve`
+    pub fn apply(&self, value: Value) -> Value {
+        self.apply_at(value, &mut Vec::new())
+    }
+
+    fn apply_at(&self, value: Value, path: &mut Vec<String>) -> Value {
+        match value {
+            Value::Array(values) => {
+                let values = values.into_iter().map(|x| self.apply_at(x, path)).collect();
+
+                if matches!(self.fallback, DuplicateKeyMode::KeyValuePairs) {
+                    tagged("array", values)
+                } else {
+                    Value::Array(values)
+                }
+            }
+            Value::Object(entries) => {
+                let mut counts: HashMap<String, usize> = HashMap::new();
+                for (key, _) in &entries {
+                    *counts.entry(key.clone()).or_default() += 1;
+                }
+
+                let mut result: Vec<(String, Value, DuplicateKeyMode)> = Vec::new();
+                for (key, value) in entries {
+                    path.push(key);
+                    let mode = self.mode(path);
+                    let value = self.apply_at(value, path);
+                    let key = path.pop().unwrap_or_default();
+
+                    let grouped = matches!(mode, DuplicateKeyMode::Group) && counts[&key] > 1;
+                    if !grouped {
+                        result.push((key, value, mode));
+                    } else if let Some((_, Value::Array(values), _)) =
+                        result.iter_mut().find(|(k, _, _)| *k == key)
+                    {
+                        values.push(value);
+                    } else {
+                        result.push((key, Value::Array(vec![value]), mode));
+                    }
+                }
+
+                let kv_pairs = result
+                    .iter()
+                    .any(|(_, _, mode)| matches!(mode, DuplicateKeyMode::KeyValuePairs));
+                let entries = result.into_iter().map(|(k, v, _)| (k, v));
+                if kv_pairs {
+                    let pairs = entries
+                        .map(|(k, v)| Value::Array(vec![Value::String(k), v]))
+                        .collect();
+                    tagged("obj", pairs)
+                } else {
+                    Value::Object(entries.collect())
+                }
+            }
+            x => x,
+        }
+    }
+}
+
+/// The shape of a container written with `DuplicateKeyMode::KeyValuePairs`
+fn tagged(kind: &str, values: Vec<Value>) -> Value {
+    Value::Object(vec![
+        (String::from("type"), Value::String(kind.to_owned())),
+        (String::from("val"), Value::Array(values)),
+    ])
+}
+
+fn matches_path(pattern: &[String], path: &[String]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((head, rest)) if head == "**" => {
+            (0..=path.len()).any(|i| matches_path(rest, &path[i..]))
+        }
+        Some((head, rest)) => match path.split_first() {
+            Some((key, path)) => matches_key(head, key) && matches_path(rest, path),
+            None => false,
+        },
+    }
+}
+
+/// Match a key against a pattern where `*` matches any sequence of characters
+fn matches_key(pattern: &str, key: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == key,
+        Some((prefix, rest)) => match key.strip_prefix(prefix) {
+            Some(key) => (0..=key.len())
+                .filter(|&i| key.is_char_boundary(i))
+                .any(|i| matches_key(rest, &key[i..])),
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(rules: &DuplicateKeyRules, data: &str) -> String {
+        let value = Value::from_slice(data.as_bytes()).unwrap();
+        serde_json::to_string(&rules.apply(value)).unwrap()
+    }
+
+    #[test]
+    fn test_matches_path() {
+        let path = |x: &str| x.split('.').map(String::from).collect::<Vec<_>>();
+        assert!(matches_path(
+            &path("countries.*.modifier"),
+            &path("countries.FRA.modifier")
+        ));
+        assert!(matches_path(
+            &path("**.history.*"),
+            &path("provinces.-1.history.owner")
+        ));
+        assert!(matches_path(&path("**.modifier*"), &path("modifier_a")));
+        assert!(!matches_path(
+            &path("countries.*"),
+            &path("countries.FRA.modifier")
+        ));
+    }
+
+    #[test]
+    fn test_group_by_path() {
+        let mut rules = DuplicateKeyRules::new(DuplicateKeyMode::Preserve);
+        rules.push("**.modifier", DuplicateKeyMode::Group);
+        let data = r#"{"a":1,"a":2,"c":{"modifier":"x","b":1,"modifier":"y"}}"#;
+        assert_eq!(
+            convert(&rules, data),
+            r#"{"a":1,"a":2,"c":{"modifier":["x","y"],"b":1}}"#
+        );
+    }
+
+    #[test]
+    fn test_kv_pairs_by_path() {
+        let mut rules = DuplicateKeyRules::new(DuplicateKeyMode::Group);
+        rules.push("history.*", DuplicateKeyMode::KeyValuePairs);
+        let data = r#"{"a":1,"a":2,"history":{"owner":"SWE","owner":"DAN"}}"#;
+        assert_eq!(
+            convert(&rules, data),
+            r#"{"a":[1,2],"history":{"type":"obj","val":[["owner","SWE"],["owner","DAN"]]}}"#
+        );
+    }
+}
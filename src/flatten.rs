@@ -0,0 +1,75 @@
+use std::{collections::HashMap, io::Write};
+
+use crate::value::Value;
+
+/// Write one `path = value` line per leaf value, so that documents can be
+/// processed with line oriented tools like grep, awk, and diff.
+///
+/// Array elements are addressed by index (`provinces[3]`) and repeated keys
+/// by their occurrence, where the second `modifier` is written as `modifier#2`
+pub(crate) fn write_flattened<W: Write>(value: &Value, mut writer: W) -> anyhow::Result<()> {
+    write_path(value, &mut String::new(), &mut writer)?;
+    Ok(())
+}
+
+fn write_path<W: Write>(value: &Value, path: &mut String, writer: &mut W) -> anyhow::Result<()> {
+    let len = path.len();
+    match value {
+        Value::Array(values) if !values.is_empty() => {
+            for (i, value) in values.iter().enumerate() {
+                path.push_str(&format!("[{}]", i));
+                write_path(value, path, writer)?;
+                path.truncate(len);
+            }
+        }
+        Value::Object(entries) if !entries.is_empty() => {
+            let mut occurrences: HashMap<&str, usize> = HashMap::new();
+            for (key, value) in entries {
+                let occurrence = occurrences.entry(key.as_str()).or_default();
+                *occurrence += 1;
+
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(key);
+                if *occurrence > 1 {
+                    path.push_str(&format!("#{}", occurrence));
+                }
+
+                write_path(value, path, writer)?;
+                path.truncate(len);
+            }
+        }
+        leaf => {
+            // Strings are json encoded so that they stay on one line and are
+            // distinguishable from numbers and booleans
+            writeln!(writer, "{} = {}", path, serde_json::to_string(leaf)?)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_flattened() {
+        let data = r#"{"countries":{"FRA":{"treasury":532.1,"modifier":"a","modifier":"b b"}},"ids":[1,{"x":true}],"empty":{},"none":null}"#;
+        let value = Value::from_slice(data.as_bytes()).unwrap();
+        let mut out = Vec::new();
+        write_flattened(&value, &mut out).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            r#"countries.FRA.treasury = 532.1
+countries.FRA.modifier = "a"
+countries.FRA.modifier#2 = "b b"
+ids[0] = 1
+ids[1].x = true
+empty = {}
+none = null
+"#
+        );
+    }
+}
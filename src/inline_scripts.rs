@@ -0,0 +1,433 @@
+use anyhow::{anyhow, bail, Context};
+use jomini::{Scalar, TextTape, TextToken};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs,
+    ops::Range,
+    path::{Component, Path},
+};
+
+use crate::{interpolation::Splice, json::UTF8_BOM};
+
+/// Directory, relative to the root of a mod, that inline scripts are read from
+const INLINE_SCRIPTS: &str = "common/inline_scripts";
+
+/// Replace every `inline_script = { script = path PARAM = value }` (or
+/// `inline_script = path`) entry with the contents of
+/// `common/inline_scripts/<path>.txt` under the mod root, with each `$PARAM$`
+/// replaced by its value. Like the game, scripts are included as text and
+/// may include inline scripts of their own. Parameters that the entry doesn't
+/// give are left as written for `--param`. Without a mod root the data is
+/// left as is
+pub(crate) fn expand_inline_scripts<'a>(
+    data: &'a [u8],
+    mod_root: Option<&Path>,
+) -> anyhow::Result<Expansion<'a>> {
+    let unexpanded = Expansion {
+        data: Cow::Borrowed(data),
+        scripts: Vec::new(),
+        runs: Vec::new(),
+    };
+    let Some(mod_root) = mod_root else {
+        return Ok(unexpanded);
+    };
+
+    let mut expander = Expander {
+        mod_root,
+        out: Vec::new(),
+        scripts: Vec::new(),
+        runs: Vec::new(),
+        stack: Vec::new(),
+    };
+    expander.expand(data, None)?;
+    if expander.scripts.is_empty() {
+        return Ok(unexpanded);
+    }
+
+    Ok(Expansion {
+        data: Cow::Owned(expander.out),
+        scripts: expander.scripts,
+        runs: expander.runs,
+    })
+}
+
+/// Data with its inline scripts expanded and where each part of it was
+/// copied from
+pub(crate) struct Expansion<'a> {
+    data: Cow<'a, [u8]>,
+
+    /// the path and contents, with parameters substituted, of each script
+    scripts: Vec<(String, Vec<u8>)>,
+
+    /// the runs of the expanded data, in order
+    runs: Vec<Run>,
+}
+
+impl Expansion<'_> {
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Where each run of the expanded data was copied from so that errors
+    /// are reported against the file that they are in
+    pub(crate) fn splices(&self) -> Vec<Splice<'_>> {
+        self.runs
+            .iter()
+            .map(|run| Splice {
+                start: run.start,
+                end: run.end,
+                file: run.script.map(|i| {
+                    let (name, data) = &self.scripts[i];
+                    (name.as_str(), data.as_slice())
+                }),
+                offset: run.offset,
+            })
+            .collect()
+    }
+}
+
+/// A run of the expanded data copied from the original data or a script
+struct Run {
+    start: usize,
+    end: usize,
+
+    /// the index of the script the run was copied from, `None` for the
+    /// original data
+    script: Option<usize>,
+
+    /// the offset of the run within the data it was copied from
+    offset: usize,
+}
+
+struct Expander<'r> {
+    mod_root: &'r Path,
+    out: Vec<u8>,
+    scripts: Vec<(String, Vec<u8>)>,
+    runs: Vec<Run>,
+
+    /// the scripts being expanded, outermost first
+    stack: Vec<String>,
+}
+
+impl Expander<'_> {
+    /// Append the expansion of the data, which is the original data or the
+    /// script at the index
+    fn expand(&mut self, data: &[u8], script: Option<usize>) -> anyhow::Result<()> {
+        let needle = b"inline_script";
+        if !data.windows(needle.len()).any(|x| x == needle) {
+            self.copy(data, 0..data.len(), script);
+            return Ok(());
+        }
+
+        let tape = match self.stack.last() {
+            Some(script) => TextTape::from_slice(data)
+                .with_context(|| format!("Failed to parse inline script: {}", script))?,
+            None => TextTape::from_slice(data)?,
+        };
+        let tokens = tape.tokens();
+        let mut entries = Vec::new();
+        find_entries(tokens, 0, tokens.len(), &mut entries);
+
+        let mut position = 0;
+        for (key, value) in entries {
+            let entry = InlineScript::new(data, tokens, key, value)?;
+            if let Some(start) = self.stack.iter().position(|x| *x == entry.script) {
+                let mut cycle = self.stack[start..].to_vec();
+                cycle.push(entry.script);
+                bail!("Recursive inline script: {}", cycle.join(" -> "));
+            }
+
+            // Scripts can't reach outside of the inline scripts directory
+            let relative = Path::new(&entry.script);
+            if !relative
+                .components()
+                .all(|x| matches!(x, Component::Normal(_)))
+            {
+                bail!(
+                    "Expected an inline script path within {}: {}",
+                    INLINE_SCRIPTS,
+                    entry.script
+                );
+            }
+
+            let path = self
+                .mod_root
+                .join(INLINE_SCRIPTS)
+                .join(format!("{}.txt", entry.script));
+            let contents = fs::read(&path)
+                .with_context(|| format!("Failed to read inline script: {}", path.display()))?;
+            let contents = contents.strip_prefix(UTF8_BOM).unwrap_or(&contents);
+            let contents = substitute(contents, &entry.params);
+
+            self.copy(data, position..entry.span.start, script);
+            let index = self.scripts.len();
+            self.scripts.push((path.display().to_string(), Vec::new()));
+            self.stack.push(entry.script);
+            self.expand(&contents, Some(index))?;
+            self.stack.pop();
+            self.scripts[index].1 = contents;
+
+            // The script may end with a comment, which mustn't swallow the
+            // text that follows the entry
+            self.out.push(b'\n');
+            position = entry.span.end;
+        }
+
+        self.copy(data, position..data.len(), script);
+        Ok(())
+    }
+
+    /// Append the range of the data, recording where it was copied from
+    fn copy(&mut self, data: &[u8], range: Range<usize>, script: Option<usize>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let start = self.out.len();
+        self.out.extend_from_slice(&data[range.clone()]);
+        self.runs.push(Run {
+            start,
+            end: self.out.len(),
+            script,
+            offset: range.start,
+        });
+    }
+}
+
+/// Collect the key and value token indices of the `inline_script` entries
+/// of an object
+fn find_entries(tokens: &[TextToken], mut i: usize, end: usize, entries: &mut Vec<(usize, usize)>) {
+    while i < end {
+        if matches!(tokens[i], TextToken::MixedContainer) {
+            return;
+        }
+
+        let key = i;
+        i += 1;
+        if i < end && matches!(tokens[i], TextToken::Operator(_)) {
+            i += 1;
+        }
+        if i >= end {
+            return;
+        }
+
+        if matches!(&tokens[key], TextToken::Unquoted(x) if x.as_bytes() == b"inline_script") {
+            entries.push((key, i));
+            i = match tokens[i] {
+                TextToken::Object { end, .. } | TextToken::Array { end, .. } => end + 1,
+                _ => i + 1,
+            };
+        } else {
+            i = find_in_value(tokens, i, entries);
+        }
+    }
+}
+
+/// Collect the `inline_script` entries within the value at the index and
+/// return the index of the token that follows the value
+fn find_in_value(tokens: &[TextToken], i: usize, entries: &mut Vec<(usize, usize)>) -> usize {
+    match tokens[i] {
+        TextToken::Object { end, .. } => {
+            find_entries(tokens, i + 1, end, entries);
+            end + 1
+        }
+        TextToken::Array { end, .. } => {
+            let mut j = i + 1;
+            while j < end {
+                j = find_in_value(tokens, j, entries);
+            }
+            end + 1
+        }
+        TextToken::Header(_) => find_in_value(tokens, i + 1, entries),
+        _ => i + 1,
+    }
+}
+
+/// An `inline_script` entry
+struct InlineScript {
+    /// the bytes of the entry, from its key to the end of its value
+    span: Range<usize>,
+
+    /// the path of the script without the `.txt` extension
+    script: String,
+
+    /// the values of the parameters given to the script
+    params: HashMap<String, Vec<u8>>,
+}
+
+impl InlineScript {
+    fn new(data: &[u8], tokens: &[TextToken], key: usize, value: usize) -> anyhow::Result<Self> {
+        let TextToken::Unquoted(key_scalar) = &tokens[key] else {
+            bail!("Expected an inline_script key");
+        };
+        let start = offset(data, key_scalar);
+
+        let (script, params, end) = match &tokens[value] {
+            TextToken::Unquoted(x) => (
+                Some(text(x)),
+                HashMap::new(),
+                offset(data, x) + x.as_bytes().len(),
+            ),
+
+            // Skip the closing quote
+            TextToken::Quoted(x) => (
+                Some(text(x)),
+                HashMap::new(),
+                offset(data, x) + x.as_bytes().len() + 1,
+            ),
+            TextToken::Object { end: close, .. } => {
+                let mut script = None;
+                let mut params = HashMap::new();
+                let mut i = value + 1;
+                while i < *close {
+                    let name = match &tokens[i] {
+                        TextToken::Unquoted(x) | TextToken::Quoted(x) => text(x),
+                        _ => bail!("Expected the inline_script parameters to be values"),
+                    };
+                    i += 1;
+                    if matches!(tokens.get(i), Some(TextToken::Operator(_))) {
+                        i += 1;
+                    }
+
+                    let argument = match tokens.get(i) {
+                        Some(TextToken::Unquoted(x) | TextToken::Quoted(x)) => x.as_bytes(),
+                        _ => bail!(
+                            "Expected the {} parameter of an inline_script to be a value",
+                            name
+                        ),
+                    };
+                    i += 1;
+
+                    if name == "script" {
+                        script = Some(String::from_utf8_lossy(argument).into_owned());
+                    } else {
+                        params.insert(name, argument.to_vec());
+                    }
+                }
+
+                let end = braced_end(data, start + key_scalar.as_bytes().len())
+                    .ok_or_else(|| anyhow!("Expected the inline_script to be closed"))?;
+                (script, params, end)
+            }
+            _ => bail!("Expected an inline_script to be a path or an object"),
+        };
+
+        let script = script.ok_or_else(|| anyhow!("Expected an inline_script to have a script"))?;
+        Ok(InlineScript {
+            span: start..end,
+            script,
+            params,
+        })
+    }
+}
+
+/// The byte offset of a scalar of the tape parsed from the data
+fn offset(data: &[u8], scalar: &Scalar) -> usize {
+    scalar.as_bytes().as_ptr() as usize - data.as_ptr() as usize
+}
+
+fn text(scalar: &Scalar) -> String {
+    String::from_utf8_lossy(scalar.as_bytes()).into_owned()
+}
+
+/// The offset after the brace that closes the first brace at or after the
+/// offset, skipping over quoted text and comments
+fn braced_end(data: &[u8], mut i: usize) -> Option<usize> {
+    let mut depth = 0;
+    while i < data.len() {
+        match data[i] {
+            b'{' => depth += 1,
+            b'}' if depth == 1 => return Some(i + 1),
+            b'}' => depth -= 1,
+            b'"' => {
+                i += 1;
+                while i < data.len() && data[i] != b'"' {
+                    if data[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            b'#' => {
+                while i < data.len() && data[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    None
+}
+
+/// Replace each `$NAME$` with the value of the parameter, leaving those that
+/// aren't given as written
+fn substitute(data: &[u8], params: &HashMap<String, Vec<u8>>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut rest = data;
+    while let Some(start) = rest.iter().position(|&b| b == b'$') {
+        out.extend_from_slice(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after.iter().position(|&b| b == b'$').and_then(|end| {
+            let name = std::str::from_utf8(&after[..end]).ok()?;
+            Some((params.get(name)?, end))
+        });
+
+        match value {
+            Some((value, end)) => {
+                out.extend_from_slice(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push(b'$');
+                rest = after;
+            }
+        }
+    }
+
+    out.extend_from_slice(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_substitute() {
+        let mut params = HashMap::new();
+        params.insert(String::from("AMOUNT"), b"50".to_vec());
+        let actual = substitute(b"add_gold = $AMOUNT$ title = $TITLE$ x = $AMOUNT$", &params);
+        assert_eq!(actual, b"add_gold = 50 title = $TITLE$ x = 50".to_vec());
+    }
+
+    #[test]
+    fn test_braced_end() {
+        let data = b"inline_script = { script = \"a}\" # }\n b = { } }\nc = d";
+        assert_eq!(braced_end(data, 13), Some(data.len() - 6));
+    }
+
+    #[test]
+    fn test_script_outside_of_inline_scripts() {
+        let root = Path::new("tests").join("fixtures").join("inline_mod");
+        for data in [
+            &b"inline_script = ../../descriptor"[..],
+            b"inline_script = { script = \"/etc/passwd\" }",
+            b"inline_script = effects/../../descriptor",
+        ] {
+            let err = expand_inline_scripts(data, Some(root.as_path())).unwrap_err();
+            assert!(err.to_string().contains("within common/inline_scripts"));
+        }
+    }
+
+    #[test]
+    fn test_find_entries() {
+        let data = b"a = { inline_script = x } inline_script = { script = y } list = { { inline_script = z } }";
+        let tape = TextTape::from_slice(data).unwrap();
+        let mut entries = Vec::new();
+        find_entries(tape.tokens(), 0, tape.tokens().len(), &mut entries);
+        assert_eq!(entries.len(), 3);
+    }
+}
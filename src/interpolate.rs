@@ -0,0 +1,102 @@
+use anyhow::Context;
+use argh::FromArgs;
+use jomini::TextTape;
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+use crate::{
+    inline_scripts::expand_inline_scripts,
+    interpolation::{InterpolatedTape, InterpolationOptions},
+    json::{load_variables, parse_input_encoding, parse_params, resolve_encoding, UTF8_BOM},
+};
+
+/// resolve variables in a game file and write it back as game script
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "interpolate")]
+pub(crate) struct InterpolateCommand {
+    /// specify the encoding of the input and output: auto | utf-8 | windows-1252
+    #[argh(option, short = 'f', default = "String::from(\"auto\")")]
+    format: String,
+
+    /// file or directory of scripted variables to load before interpolating,
+    /// may be repeated with later files overriding earlier ones
+    #[argh(option)]
+    vars_from: Vec<PathBuf>,
+
+    /// parameter of scripted effects and triggers as `NAME=VALUE` that
+    /// replaces `$NAME$` and selects `[[NAME]` blocks, may be repeated
+    #[argh(option, long = "param")]
+    params: Vec<String>,
+
+    /// root directory of the mod that `inline_script` entries are read from
+    /// (`common/inline_scripts`) and expanded
+    #[argh(option)]
+    mod_root: Option<PathBuf>,
+
+    /// write the script to this file instead of stdout
+    #[argh(option, short = 'o')]
+    out: Option<PathBuf>,
+
+    /// file to interpolate
+    #[argh(positional)]
+    file: PathBuf,
+}
+
+impl InterpolateCommand {
+    pub(crate) fn exec(&self) -> anyhow::Result<i32> {
+        let encoding = parse_input_encoding(&self.format)?;
+        let variables = load_variables(&self.vars_from, encoding)?;
+        let params = parse_params(&self.params)?;
+        let data = fs::read(&self.file)
+            .with_context(|| format!("Failed to read: {}", self.file.display()))?;
+        let encoding = resolve_encoding(encoding, &self.file, &data);
+
+        // Keep the BOM that the games require some files to start with
+        let bom = data.starts_with(UTF8_BOM);
+        let body = data.strip_prefix(UTF8_BOM).unwrap_or(&data);
+        let expansion = expand_inline_scripts(body, self.mod_root.as_deref())?;
+        let splices = expansion.splices();
+        let original = body;
+        let body = expansion.data();
+
+        let tape = TextTape::from_slice(body)?;
+        let name = self.file.display().to_string();
+        let mut options = InterpolationOptions::new(encoding)
+            .with_variables(variables)
+            .with_source(&name, body)
+            .with_splices(original, &splices);
+        if let Some(params) = params {
+            options = options.with_params(params);
+        }
+        let interpolated = InterpolatedTape::from_tape_with_options(&tape, options)
+            .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        for warning in interpolated.warnings() {
+            eprintln!("warning: {}\n", warning);
+        }
+
+        let mut out = Vec::new();
+        if bom {
+            out.extend_from_slice(UTF8_BOM);
+        }
+        interpolated.to_script_writer(&mut out)?;
+
+        match &self.out {
+            Some(path) => fs::write(path, &out)
+                .with_context(|| format!("Failed to write: {}", path.display()))?,
+            None => {
+                let stdout = io::stdout();
+                let mut writer = BufWriter::new(stdout.lock());
+                let result = writer.write_all(&out).and_then(|_| writer.flush());
+                match result {
+                    Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(0)
+    }
+}
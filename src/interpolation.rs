@@ -1,21 +1,702 @@
-use crate::json::Encoding;
+use crate::{json::Encoding, text::encode_windows1252};
 use jomini::{text::ObjectReader, Scalar, TextTape, TextToken, Utf8Encoding, Windows1252Encoding};
-use std::collections::{HashMap, HashSet};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    ops::Range,
+};
 
 pub struct InterpolatedTape<'a> {
     original_tape: &'a TextTape<'a>,
     interpolated_strings: Vec<String>,
     token_overrides: HashMap<usize, usize>, // token_index -> string_index
-    variable_declarations: HashSet<String>, // variable names that were declared
+    declarations: HashSet<usize>,           // token indices of variable declarations
+    parameter_blocks: HashMap<usize, bool>, // token index of `[[NAME]` -> contents kept
+    quoted_overrides: HashSet<usize>,       // overrides written as quoted text
+    variables: HashMap<String, VariableValue>, // variables declared at the root
+    declared: Vec<VariableDeclaration>,     // every declaration, in order
+    undefined: Vec<VariableReference>,      // references to undeclared variables
+    errors: Vec<String>,                    // errors when they are collected
+    warnings: Vec<String>,                  // parameters left unexpanded
+    encoding: Encoding,                     // encoding of the tape's text
+    preserve_operators: bool,
 }
 
-/// Decode bytes using the specified encoding
-fn decode_bytes(bytes: &[u8], encoding: Encoding) -> Result<String, Box<dyn std::error::Error>> {
-    let decoded = match encoding {
+/// A variable declaration within a tape and how it is used
+#[derive(Clone, Debug, PartialEq)]
+pub struct VariableDeclaration {
+    pub name: String,
+
+    /// the evaluated value, `None` when the declaration failed to evaluate
+    pub value: Option<VariableValue>,
+
+    /// the value as declared (eg: `@[scale * 2]`)
+    pub definition: String,
+
+    /// where the declaration is, when the source of the tape is known
+    pub location: Option<Location>,
+
+    /// the file the declaration was copied from, when it isn't the source
+    /// of the tape (eg: an inline script)
+    pub file: Option<String>,
+
+    /// whether the declaration is at the root of the tape rather than within
+    /// a block, making it visible to other files
+    pub root: bool,
+
+    /// the number of references that resolve to this declaration
+    pub uses: usize,
+}
+
+/// A use of a variable
+#[derive(Clone, Debug, PartialEq)]
+pub struct VariableReference {
+    pub name: String,
+
+    /// where the token that uses the variable is, when the source of the
+    /// tape is known
+    pub location: Option<Location>,
+
+    /// the file the token was copied from, when it isn't the source of the
+    /// tape (eg: an inline script)
+    pub file: Option<String>,
+}
+
+/// A line and column within the source of a tape, both starting at 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    fn new(data: &[u8], offset: usize, encoding: Encoding) -> Self {
+        let line_start = data[..offset]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |x| x + 1);
+        Location {
+            line: data[..offset].iter().filter(|&&b| b == b'\n').count() + 1,
+            column: decode(&data[line_start..offset], encoding).chars().count() + 1,
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The value of a scripted variable
+#[derive(Clone, Debug, PartialEq)]
+pub enum VariableValue {
+    /// `@name = 10`
+    Number(f64),
+
+    /// `@name = "gfx/icon.dds"` or `@name = yes`
+    Text(String),
+}
+
+impl VariableValue {
+    /// The value as an operand of arithmetic, as text can only be concatenated
+    fn to_number(&self) -> Result<f64, Box<dyn std::error::Error>> {
+        match self {
+            VariableValue::Number(x) => Ok(*x),
+            VariableValue::Text(x) => {
+                Err(format!("Expected a number, found text \"{}\"", x).into())
+            }
+        }
+    }
+}
+
+impl From<f64> for VariableValue {
+    fn from(value: f64) -> Self {
+        VariableValue::Number(value)
+    }
+}
+
+impl From<String> for VariableValue {
+    fn from(value: String) -> Self {
+        VariableValue::Text(value)
+    }
+}
+
+impl fmt::Display for VariableValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VariableValue::Number(x) => f.write_str(&format_numeric_value(*x)),
+            VariableValue::Text(x) => f.write_str(x),
+        }
+    }
+}
+
+/// Configures how a tape is interpolated
+pub struct InterpolationOptions<'s> {
+    encoding: Encoding,
+    variables: HashMap<String, VariableValue>,
+    params: Option<HashMap<String, String>>,
+    source: Option<Source<'s>>,
+    collect_errors: bool,
+}
+
+/// The data a tape was parsed from, used to locate errors
+#[derive(Clone, Copy)]
+struct Source<'s> {
+    name: &'s str,
+    data: &'s [u8],
+
+    /// the file's own data when the data was assembled from several files
+    original: &'s [u8],
+    splices: &'s [Splice<'s>],
+}
+
+impl<'s> Source<'s> {
+    /// The file that the offset of the data was copied from (`None` for the
+    /// source itself), that file's data, and the offset within it
+    fn resolve(&self, offset: usize) -> (Option<&'s str>, &'s [u8], usize) {
+        let Some(splice) = self.splices.iter().rev().find(|x| x.start <= offset) else {
+            return (None, self.data, offset);
+        };
+
+        // Offsets past the run are text added between runs (eg: the newline
+        // after an inline script), which is attributed to the end of the run
+        let (name, data) = match splice.file {
+            Some((name, data)) => (Some(name), data),
+            None => (None, self.original),
+        };
+        let offset = splice.offset + offset.min(splice.end) - splice.start;
+        (name, data, offset.min(data.len()))
+    }
+}
+
+/// A run of the data a tape was parsed from that was copied from elsewhere,
+/// like the contents of an expanded inline script
+#[derive(Clone, Copy, Debug)]
+pub struct Splice<'s> {
+    /// the start of the run within the data
+    pub start: usize,
+
+    /// the end of the run within the data
+    pub end: usize,
+
+    /// the name and data of the file the run was copied from, `None` for
+    /// the file's own data
+    pub file: Option<(&'s str, &'s [u8])>,
+
+    /// the offset of the run within the data it was copied from
+    pub offset: usize,
+}
+
+impl<'s> InterpolationOptions<'s> {
+    /// Decode text tokens with the given encoding
+    pub fn new(encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            variables: HashMap::new(),
+            params: None,
+            source: None,
+            collect_errors: false,
+        }
+    }
+
+    /// Variables defined outside of the tape (eg: scripted variables). The
+    /// tape may redefine them
+    pub fn with_variables<V: Into<VariableValue>>(mut self, variables: HashMap<String, V>) -> Self {
+        self.variables = variables
+            .into_iter()
+            .map(|(name, value)| (name, value.into()))
+            .collect();
+        self
+    }
+
+    /// Parameters of scripted effects and triggers. Each `$NAME$` is replaced
+    /// by its value, and `[[NAME] ... ]` blocks are kept only when the
+    /// parameter is given (`[[!NAME] ... ]` blocks only when it is not)
+    pub fn with_params(mut self, params: HashMap<String, String>) -> Self {
+        self.params = Some(params);
+        self
+    }
+
+    /// The file name and data that the tape was parsed from so that errors
+    /// report the line and column of the offending expression
+    pub fn with_source(mut self, name: &'s str, data: &'s [u8]) -> Self {
+        self.source = Some(Source {
+            name,
+            data,
+            original: data,
+            splices: &[],
+        });
+        self
+    }
+
+    /// The source data was assembled from the file's own data and other
+    /// files, as described by the runs, so that errors are located within
+    /// the file they were copied from. Must follow `with_source`
+    pub fn with_splices(mut self, original: &'s [u8], splices: &'s [Splice<'s>]) -> Self {
+        if let Some(source) = &mut self.source {
+            source.original = original;
+            source.splices = splices;
+        }
+        self
+    }
+
+    /// Record errors on the interpolated tape instead of failing, leaving
+    /// the offending tokens as written
+    pub fn with_collected_errors(mut self) -> Self {
+        self.collect_errors = true;
+        self
+    }
+}
+
+/// The block structure of a tape and the variables declared in each block.
+/// A variable declared within a block shadows those of enclosing blocks and
+/// is not visible outside of it
+struct Scopes {
+    /// index of the token that opens the block enclosing each token, `None`
+    /// for tokens at the root
+    parents: Vec<Option<usize>>,
+
+    /// whether each token is the key of an entry
+    keys: Vec<bool>,
+
+    /// token indices of the declarations of each variable, in order, by the
+    /// block they are declared in
+    declarations: HashMap<Option<usize>, HashMap<String, Vec<usize>>>,
+
+    /// variables defined outside of the tape, visible from the root
+    external: HashMap<String, VariableValue>,
+}
+
+/// What a variable name refers to at a position in the tape
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Binding {
+    /// the declaration whose key is at the token index
+    Declaration(usize),
+
+    /// a variable defined outside of the tape
+    External,
+}
+
+impl Scopes {
+    /// Walk the tape with the given variables defined outside of it
+    fn new(tokens: &[TextToken], external: HashMap<String, VariableValue>) -> Self {
+        let mut scopes = Scopes {
+            parents: vec![None; tokens.len()],
+            keys: vec![false; tokens.len()],
+            declarations: HashMap::new(),
+            external,
+        };
+        scopes.walk_object(tokens, 0, tokens.len(), None);
+        scopes
+    }
+
+    fn walk_object(
+        &mut self,
+        tokens: &[TextToken],
+        mut i: usize,
+        end: usize,
+        block: Option<usize>,
+    ) {
+        while i < end {
+            // Once an object has a bare value, operators are written out
+            if matches!(tokens[i], TextToken::MixedContainer) {
+                self.parents[i] = block;
+                return self.walk_values(tokens, i + 1, end, block);
+            }
+
+            // The contents of a parameter block (`[[NAME] ... ]`) belong to
+            // the enclosing block
+            if let (
+                TextToken::Parameter(_) | TextToken::UndefinedParameter(_),
+                Some(TextToken::Object { end: close, .. } | TextToken::Array { end: close, .. }),
+            ) = (&tokens[i], tokens.get(i + 1))
+            {
+                let close = *close;
+                self.parents[i] = block;
+                self.parents[i + 1] = block;
+                self.parents[close] = block;
+                if matches!(tokens[i + 1], TextToken::Object { .. }) {
+                    self.walk_object(tokens, i + 2, close, block);
+                } else {
+                    self.walk_values(tokens, i + 2, close, block);
+                }
+                i = close + 1;
+                continue;
+            }
+
+            self.parents[i] = block;
+            self.keys[i] = true;
+            i += 1;
+            if i < end && matches!(tokens[i], TextToken::Operator(_)) {
+                self.parents[i] = block;
+                i += 1;
+            }
+            i = self.walk_value(tokens, i, end, block);
+        }
+    }
+
+    fn walk_values(
+        &mut self,
+        tokens: &[TextToken],
+        mut i: usize,
+        end: usize,
+        block: Option<usize>,
+    ) {
+        while i < end {
+            if i + 1 < end && matches!(tokens[i + 1], TextToken::Operator(_)) {
+                self.parents[i] = block;
+                self.parents[i + 1] = block;
+                self.keys[i] = true;
+                i += 2;
+            }
+            i = self.walk_value(tokens, i, end, block);
+        }
+    }
+
+    /// Walk the value at the index and return the index of the next token
+    fn walk_value(
+        &mut self,
+        tokens: &[TextToken],
+        i: usize,
+        end: usize,
+        block: Option<usize>,
+    ) -> usize {
+        if i >= end {
+            return i;
+        }
+
+        self.parents[i] = block;
+        match tokens[i] {
+            TextToken::Object { end: close, .. } => {
+                self.walk_object(tokens, i + 1, close, Some(i));
+                self.parents[close] = block;
+                close + 1
+            }
+            TextToken::Array { end: close, .. } => {
+                self.walk_values(tokens, i + 1, close, Some(i));
+                self.parents[close] = block;
+                close + 1
+            }
+            TextToken::Header(_) => self.walk_value(tokens, i + 1, end, block),
+            _ => i + 1,
+        }
+    }
+
+    fn is_key(&self, token_index: usize) -> bool {
+        self.keys[token_index]
+    }
+
+    /// The index of the token that opens the block enclosing the token
+    fn block(&self, token_index: usize) -> Option<usize> {
+        self.parents[token_index]
+    }
+
+    /// Record a declaration of the variable in the block enclosing the token.
+    /// Declarations must be recorded in token order
+    fn declare(&mut self, token_index: usize, name: &str) {
+        let block = self.parents[token_index];
+        self.declarations
+            .entry(block)
+            .or_default()
+            .entry(name.to_owned())
+            .or_default()
+            .push(token_index);
+    }
+
+    fn declared(&self, block: Option<usize>, name: &str) -> &[usize] {
+        self.declarations
+            .get(&block)
+            .and_then(|x| x.get(name))
+            .map_or(&[], |x| x.as_slice())
+    }
+
+    /// Resolve a variable name used at the token. The game reads files top
+    /// to bottom, so this is the latest declaration that precedes the token
+    /// in the nearest block. Otherwise it is a variable defined outside of
+    /// the tape and, failing that, a forward reference to the next
+    /// declaration in the nearest block
+    fn resolve(&self, token_index: usize, name: &str) -> Option<Binding> {
+        let mut block = self.parents[token_index];
+        loop {
+            let declarations = self.declared(block, name);
+            let preceding = declarations.partition_point(|&x| x < token_index);
+            if preceding > 0 {
+                return Some(Binding::Declaration(declarations[preceding - 1]));
+            }
+
+            match block {
+                Some(x) => block = self.parents[x],
+                None => break,
+            }
+        }
+
+        if self.external.contains_key(name) {
+            return Some(Binding::External);
+        }
+
+        let mut block = self.parents[token_index];
+        loop {
+            let declarations = self.declared(block, name);
+            if let Some(&x) = declarations.iter().find(|&&x| x > token_index) {
+                return Some(Binding::Declaration(x));
+            }
+
+            block = self.parents[block?];
+        }
+    }
+
+    /// The last declaration of each variable declared at the root
+    fn root_declarations(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        self.declarations
+            .get(&None)
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, declarations)| Some((name.as_str(), *declarations.last()?)))
+    }
+}
+
+/// A variable declaration (`@name = value`)
+struct Declaration {
+    name: String,
+    definition: Definition,
+
+    /// what each variable referenced by the definition resolved to
+    dependencies: Vec<(String, Option<Binding>)>,
+}
+
+/// The value a variable is declared with
+enum Definition {
+    /// `@name = 10` or `@name = "text"`
+    Value(VariableValue),
+
+    /// `@name = @other`
+    Reference(String),
+
+    /// `@name = @[other * 2]`
+    Expression(String),
+}
+
+impl fmt::Display for Definition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Definition::Value(VariableValue::Text(x)) => write!(f, "\"{}\"", x),
+            Definition::Value(value) => write!(f, "{}", value),
+            Definition::Reference(name) => write!(f, "@{}", name),
+            Definition::Expression(expr) => write!(f, "@[{}]", expr),
+        }
+    }
+}
+
+/// The evaluation of a declaration
+#[derive(Clone, PartialEq)]
+enum State {
+    /// the declaration's dependencies are being evaluated
+    Visiting,
+
+    /// the value of the declaration, `None` when it couldn't be evaluated
+    Done(Option<VariableValue>),
+}
+
+/// Evaluate every declaration after the declarations it depends on, visiting
+/// each declaration and dependency once. Errors are recorded against the
+/// token of the offending value. Declarations that depend on a declaration
+/// that failed to evaluate are left without a value but aren't reported, as
+/// the original failure already is
+fn evaluate_declarations(
+    declarations: &BTreeMap<usize, Declaration>,
+    external: &HashMap<String, VariableValue>,
+    diagnostics: &mut Vec<(usize, String)>,
+) -> HashMap<usize, Option<VariableValue>> {
+    let mut states: HashMap<usize, State> = HashMap::with_capacity(declarations.len());
+    for &root in declarations.keys() {
+        if states.contains_key(&root) {
+            continue;
+        }
+
+        // Depth first search with an explicit stack of declarations and the
+        // position of the next dependency to visit
+        states.insert(root, State::Visiting);
+        let mut stack: Vec<(usize, usize)> = vec![(root, 0)];
+        while let Some((node, next)) = stack.last_mut() {
+            let node = *node;
+            let declaration = &declarations[&node];
+            if let Some((_, binding)) = declaration.dependencies.get(*next) {
+                *next += 1;
+                let Some(Binding::Declaration(dependency)) = *binding else {
+                    continue;
+                };
+
+                match states.get(&dependency) {
+                    None => {
+                        states.insert(dependency, State::Visiting);
+                        stack.push((dependency, 0));
+                    }
+                    Some(State::Visiting) => {
+                        let start = stack
+                            .iter()
+                            .position(|(x, _)| *x == dependency)
+                            .unwrap_or_default();
+                        let cycle: Vec<_> = stack[start..]
+                            .iter()
+                            .chain(std::iter::once(&(dependency, 0)))
+                            .map(|(x, _)| format!("@{}", declarations[x].name))
+                            .collect();
+                        let message = format!("Cyclic variable references: {}", cycle.join(" -> "));
+                        diagnostics.push((dependency + 1, message));
+                    }
+                    Some(State::Done(_)) => {}
+                }
+                continue;
+            }
+
+            stack.pop();
+            let value = match evaluate_declaration(declaration, external, &states) {
+                Ok(value) => value,
+                Err(e) => {
+                    diagnostics.push((node + 1, e));
+                    None
+                }
+            };
+            states.insert(node, State::Done(value));
+        }
+    }
+
+    states
+        .into_iter()
+        .map(|(node, state)| match state {
+            State::Done(value) => (node, value),
+            State::Visiting => (node, None),
+        })
+        .collect()
+}
+
+/// Evaluate a declaration whose dependencies have been evaluated. A
+/// dependency that is still being visited is part of a cycle
+fn evaluate_declaration(
+    declaration: &Declaration,
+    external: &HashMap<String, VariableValue>,
+    states: &HashMap<usize, State>,
+) -> Result<Option<VariableValue>, String> {
+    let mut variables = HashMap::with_capacity(declaration.dependencies.len());
+    for (name, binding) in &declaration.dependencies {
+        let value = match binding {
+            Some(Binding::External) => external[name].clone(),
+            Some(Binding::Declaration(dependency)) => match states.get(dependency) {
+                Some(State::Done(Some(value))) => value.clone(),
+                _ => return Ok(None),
+            },
+            None => continue,
+        };
+        variables.insert(name.clone(), value);
+    }
+
+    match &declaration.definition {
+        Definition::Value(value) => Ok(Some(value.clone())),
+        Definition::Reference(name) => match variables.remove(name) {
+            Some(value) => Ok(Some(value)),
+            None => Err(format!(
+                "Unresolved variable references: @{} -> @{}",
+                declaration.name, name
+            )),
+        },
+        Definition::Expression(expr) => eval_expression(expr, &variables)
+            .map(Some)
+            .map_err(|e| e.to_string()),
+    }
+}
+
+/// Every error found while interpolating a tape
+#[derive(Debug)]
+pub struct InterpolationError {
+    diagnostics: Vec<String>,
+}
+
+impl fmt::Display for InterpolationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.diagnostics.join("\n\n"))
+    }
+}
+
+impl std::error::Error for InterpolationError {}
+
+fn decode(bytes: &[u8], encoding: Encoding) -> Cow<'_, str> {
+    match encoding {
         Encoding::Utf8 => Utf8Encoding::decode(bytes),
         Encoding::Windows1252 => Windows1252Encoding::decode(bytes),
+    }
+}
+
+/// Decode bytes using the specified encoding
+fn decode_bytes(bytes: &[u8], encoding: Encoding) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(decode(bytes, encoding).into_owned())
+}
+
+/// The byte range of a token's text within the source data
+fn locate(source: Option<Source>, scalar: &Scalar) -> Option<Range<usize>> {
+    let data = source?.data;
+    let bytes = scalar.as_bytes();
+    let start = (bytes.as_ptr() as usize).checked_sub(data.as_ptr() as usize)?;
+    let end = start + bytes.len();
+    (end <= data.len()).then_some(start..end)
+}
+
+/// Format an error message with the file, line, and column of the span
+/// followed by the line with the span underlined:
+///
+/// ```text
+/// Unknown operand: foo
+///  --> common/units.txt:3:6
+///   |
+/// 3 | cost=@[foo * 2]
+///   |      ^^^^^^^^^^
+/// ```
+fn render_diagnostic(
+    message: &str,
+    source: Option<Source>,
+    span: Option<Range<usize>>,
+    encoding: Encoding,
+) -> String {
+    let (Some(source), Some(span)) = (source, span) else {
+        return message.to_owned();
     };
-    Ok(decoded.into_owned())
+
+    let (name, data, start) = source.resolve(span.start);
+    let name = name.unwrap_or(source.name);
+    let span = start..(start + span.len()).min(data.len());
+    let line_start = data[..span.start]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |x| x + 1);
+    let line_end = data[span.start..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(data.len(), |x| span.start + x);
+    let Location {
+        line: line_number,
+        column,
+    } = Location::new(data, span.start, encoding);
+
+    // Tabs are kept in the padding so the carets line up with the snippet
+    let prefix = decode(&data[line_start..span.start], encoding);
+    let padding: String = prefix
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = decode(&data[span.start..span.end.min(line_end)], encoding)
+        .chars()
+        .count()
+        .max(1);
+
+    let line = decode(&data[line_start..line_end], encoding);
+    let gutter = " ".repeat(line_number.to_string().len());
+    format!(
+        "{message}\n{gutter}--> {}:{line_number}:{column}\n{gutter} |\n{line_number} | {}\n{gutter} | {padding}{}",
+        name,
+        line.trim_end_matches('\r'),
+        "^".repeat(width),
+    )
 }
 
 impl<'a> InterpolatedTape<'a> {
@@ -25,167 +706,414 @@ impl<'a> InterpolatedTape<'a> {
         tape: &'a TextTape<'a>,
         encoding: Encoding,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut variables: HashMap<String, f64> = HashMap::new();
+        Self::from_tape_with_options(tape, InterpolationOptions::new(encoding))
+    }
+
+    /// Create a new InterpolatedTape from an existing tape with interpolations applied
+    /// according to the options
+    pub fn from_tape_with_options(
+        tape: &'a TextTape<'a>,
+        options: InterpolationOptions<'_>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let InterpolationOptions {
+            encoding,
+            variables,
+            params,
+            source,
+            collect_errors,
+        } = options;
         let mut interpolated_strings = Vec::new();
         let mut token_overrides = HashMap::new();
-        let mut skip_interpolation: HashSet<usize> = HashSet::new();
-        let mut variable_declarations: HashSet<String> = HashSet::new();
+        let mut quoted_overrides = HashSet::new();
+        let mut diagnostics: Vec<(usize, String)> = Vec::new();
+
+        // The number of references to each declaration and the token index
+        // of references to variables that aren't declared
+        let mut uses: HashMap<usize, usize> = HashMap::new();
+        let mut undefined: Vec<(usize, String)> = Vec::new();
 
-        // Multiple passes to collect variable definitions (handle forward references)
         let tokens = tape.tokens();
-        let mut unresolved_refs: Vec<(usize, String, String)> = Vec::new(); // (index, var_name, referenced_var)
-
-        // Keep iterating until no more variables can be resolved
-        let mut progress = true;
-        while progress {
-            progress = false;
-            let mut i = 0;
-
-            while i < tokens.len() {
-                if let TextToken::Unquoted(scalar) = &tokens[i] {
-                    let text = decode_bytes(scalar.as_bytes(), encoding)?;
-
-                    // Variable definition: @var_name
-                    if text.starts_with('@') && !text.starts_with("@[") {
-                        let var_name = &text[1..];
-
-                        // Skip if already processed
-                        if variables.contains_key(var_name) {
-                            i += 1;
-                            continue;
-                        }
+        let mut scopes = Scopes::new(tokens, variables);
+
+        // Select the parameter blocks to keep. Tokens of dropped blocks are
+        // neither evaluated nor written out
+        let mut parameter_blocks: HashMap<usize, bool> = HashMap::new();
+        let mut dropped = vec![false; tokens.len()];
+        let mut warnings: Vec<(usize, String)> = Vec::new();
+        if let Some(params) = &params {
+            for (i, token) in tokens.iter().enumerate() {
+                let (name, keep_if_given) = match token {
+                    TextToken::Parameter(name) => (name, true),
+                    TextToken::UndefinedParameter(name) => (name, false),
+                    _ => continue,
+                };
 
-                        // Look for the value (no = operator in tokens, it's consumed by parser)
-                        if i + 1 < tokens.len() {
-                            if let TextToken::Unquoted(value_scalar) = &tokens[i + 1] {
-                                let value_text = decode_bytes(value_scalar.as_bytes(), encoding)?;
-
-                                // Handle @var = @[expression] format
-                                if value_text.starts_with("@[") && value_text.ends_with("]") {
-                                    let expr = &value_text[2..value_text.len() - 1];
-                                    let computed_value = eval_expression(expr, &variables)?;
-                                    variables.insert(var_name.to_string(), computed_value);
-                                    skip_interpolation.insert(i);
-                                    // Mark this as a variable declaration
-                                    variable_declarations.insert(format!("@{}", var_name));
-                                    progress = true;
-                                }
-                                // Handle @var = @other_var format (direct variable reference)
-                                else if value_text.starts_with("@")
-                                    && !value_text.starts_with("@[")
-                                {
-                                    let referenced_var = &value_text[1..];
-                                    if let Some(&referenced_value) = variables.get(referenced_var) {
-                                        variables.insert(var_name.to_string(), referenced_value);
-                                        skip_interpolation.insert(i);
-                                        // Mark this as a variable declaration
-                                        variable_declarations.insert(format!("@{}", var_name));
-                                        progress = true;
-                                    } else {
-                                        // Store for later resolution
-                                        unresolved_refs.push((
-                                            i,
-                                            var_name.to_string(),
-                                            referenced_var.to_string(),
-                                        ));
-                                    }
-                                }
-                                // Handle @var = number format
-                                else if let Ok(value) = parse_f64(value_scalar.as_bytes()) {
-                                    variables.insert(var_name.to_string(), value);
-                                    skip_interpolation.insert(i);
-                                    // Mark this as a variable declaration
-                                    variable_declarations.insert(format!("@{}", var_name));
-                                    progress = true;
-                                }
-                            }
-                        }
+                let container = match tokens.get(i + 1) {
+                    Some(TextToken::Object { end, .. }) => Some((*end, false)),
+                    Some(TextToken::Array { end, .. }) => Some((*end, true)),
+                    _ => None,
+                };
+                let Some((end, values)) = container.filter(|_| !dropped[i]) else {
+                    continue;
+                };
+
+                let name = decode(name.as_bytes(), encoding);
+                let keep = params.contains_key(name.as_ref()) == keep_if_given;
+                parameter_blocks.insert(i, keep);
+                if !keep {
+                    dropped[i..=end].fill(true);
+                } else if values && end > i + 2 {
+                    // Values can only be spliced into an array
+                    let parent = scopes.block(i).map(|x| &tokens[x]);
+                    if matches!(parent, Some(TextToken::Array { .. })) {
+                        continue;
                     }
+
+                    let message = format!(
+                        "The values of the [[{}] block can't be placed within an object",
+                        name
+                    );
+                    diagnostics.push((i, message));
                 }
-                i += 1;
             }
+        } else {
+            // Without parameters, they are written back as they are, which
+            // is rarely intended when interpolating
+            for (i, token) in tokens.iter().enumerate() {
+                let names = match token {
+                    TextToken::Parameter(name) => {
+                        vec![format!("[[{}]", decode(name.as_bytes(), encoding))]
+                    }
+                    TextToken::UndefinedParameter(name) => {
+                        vec![format!("[[!{}]", decode(name.as_bytes(), encoding))]
+                    }
+                    TextToken::Unquoted(x) | TextToken::Quoted(x) => {
+                        parameter_names(&decode(x.as_bytes(), encoding))
+                            .into_iter()
+                            .map(|name| format!("${}$", name))
+                            .collect()
+                    }
+                    _ => continue,
+                };
 
-            // Try to resolve any unresolved references
-            let mut resolved_indices = Vec::new();
-            for (idx, (token_index, var_name, referenced_var)) in unresolved_refs.iter().enumerate()
-            {
-                if let Some(&referenced_value) = variables.get(referenced_var) {
-                    variables.insert(var_name.clone(), referenced_value);
-                    skip_interpolation.insert(*token_index);
-                    variable_declarations.insert(format!("@{}", var_name));
-                    resolved_indices.push(idx);
-                    progress = true;
+                for name in names {
+                    warnings.push((i, format!("Unexpanded parameter: {}", name)));
                 }
             }
+        }
+
+        // Collect the declarations (`@name = value` entries) in one pass
+        let mut declarations: BTreeMap<usize, Declaration> = BTreeMap::new();
+        for (i, token) in tokens.iter().enumerate() {
+            let TextToken::Unquoted(scalar) = token else {
+                continue;
+            };
+            if dropped[i] || !scopes.is_key(i) || !scalar.as_bytes().starts_with(b"@") {
+                continue;
+            }
+
+            let text = decode_bytes(scalar.as_bytes(), encoding)?;
+            if text.starts_with("@[") {
+                continue;
+            }
 
-            // Remove resolved references from the unresolved list
-            for &idx in resolved_indices.iter().rev() {
-                unresolved_refs.remove(idx);
+            // The value directly follows the key (no = operator in tokens,
+            // it's consumed by the parser)
+            let (value_scalar, quoted) = match tokens.get(i + 1) {
+                Some(TextToken::Unquoted(x)) => (x, false),
+                Some(TextToken::Quoted(x)) => (x, true),
+                _ => continue,
+            };
+            let mut value_text = decode_bytes(value_scalar.as_bytes(), encoding)?;
+
+            // Undefined parameters are reported by the second pass
+            if let Some(params) = &params {
+                value_text = substitute_params(&value_text, params).unwrap_or(value_text);
             }
+
+            let definition = if quoted {
+                Definition::Value(VariableValue::Text(value_text))
+            } else if value_text.starts_with("@[") && value_text.ends_with(']') {
+                Definition::Expression(value_text[2..value_text.len() - 1].to_string())
+            } else if let Some(referenced_var) = value_text.strip_prefix('@') {
+                Definition::Reference(referenced_var.to_string())
+            } else if let Ok(value) = parse_f64(value_text.as_bytes()) {
+                Definition::Value(VariableValue::Number(value))
+            } else {
+                Definition::Value(VariableValue::Text(value_text))
+            };
+
+            let name = text[1..].to_string();
+            scopes.declare(i, &name);
+            declarations.insert(
+                i,
+                Declaration {
+                    name,
+                    definition,
+                    dependencies: Vec::new(),
+                },
+            );
         }
 
-        // Check for any remaining unresolved references
-        if !unresolved_refs.is_empty() {
-            let unresolved_names: Vec<String> = unresolved_refs
-                .iter()
-                .map(|(_, var_name, referenced_var)| {
-                    format!("@{} -> @{}", var_name, referenced_var)
+        // Resolve what each declaration depends on, now that every
+        // declaration is known, and evaluate them in dependency order
+        for (&i, declaration) in declarations.iter_mut() {
+            let names = match &declaration.definition {
+                Definition::Value(_) => Vec::new(),
+                Definition::Reference(name) => vec![name.clone()],
+                Definition::Expression(expr) => expression_names(expr),
+            };
+
+            declaration.dependencies = names
+                .into_iter()
+                .map(|name| {
+                    let binding = scopes.resolve(i, &name);
+                    match binding {
+                        Some(Binding::Declaration(x)) => *uses.entry(x).or_default() += 1,
+                        Some(Binding::External) => {}
+                        None => undefined.push((i + 1, name.clone())),
+                    }
+                    (name, binding)
                 })
                 .collect();
-            return Err(format!(
-                "Unresolved variable references: {}",
-                unresolved_names.join(", ")
-            )
-            .into());
         }
+        let values = evaluate_declarations(&declarations, &scopes.external, &mut diagnostics);
 
         // Second pass: find and store interpolations
-        let mut i = 0;
-        while i < tokens.len() {
-            if let TextToken::Unquoted(scalar) = &tokens[i] {
-                let text = decode_bytes(scalar.as_bytes(), encoding)?;
-
-                // Variable interpolation: @[expression] or @var_name
-                if text.starts_with("@[") && text.ends_with("]") {
-                    let expr = &text[2..text.len() - 1];
-                    let computed_value = eval_expression(expr, &variables)?;
-                    let value_str = format_numeric_value(computed_value);
-                    let string_index = interpolated_strings.len();
-                    interpolated_strings.push(value_str);
-                    token_overrides.insert(i, string_index);
-                } else if text.starts_with("@")
-                    && !text.starts_with("@[")
-                    && !skip_interpolation.contains(&i)
-                {
-                    let var_name = &text[1..];
-                    if let Some(&value) = variables.get(var_name) {
-                        let value_str = format_numeric_value(value);
-                        let string_index = interpolated_strings.len();
-                        interpolated_strings.push(value_str);
-                        token_overrides.insert(i, string_index);
+        for (i, token) in tokens.iter().enumerate() {
+            let (TextToken::Unquoted(scalar) | TextToken::Quoted(scalar)) = token else {
+                continue;
+            };
+
+            let declaration = declarations.contains_key(&i)
+                || i.checked_sub(1)
+                    .is_some_and(|x| declarations.contains_key(&x));
+            if dropped[i]
+                || declaration
+                || (params.is_none() && !scalar.as_bytes().starts_with(b"@"))
+            {
+                continue;
+            }
+
+            let raw = decode_bytes(scalar.as_bytes(), encoding)?;
+            let text = match &params {
+                Some(params) => match substitute_params(&raw, params) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        diagnostics.push((i, e));
+                        continue;
+                    }
+                },
+                None => raw.clone(),
+            };
+            let quoted = matches!(token, TextToken::Quoted(_));
+
+            // The value of a variable used at the token, or `None` for a
+            // declaration that failed to evaluate
+            let mut lookup = |name: &str| match scopes.resolve(i, name) {
+                Some(Binding::External) => Some(scopes.external.get(name).cloned()),
+                Some(Binding::Declaration(x)) => {
+                    *uses.entry(x).or_default() += 1;
+                    Some(values.get(&x).cloned().flatten())
+                }
+                None => {
+                    undefined.push((i, name.to_owned()));
+                    None
+                }
+            };
+
+            // Variable interpolation: @[expression] or @var_name, in keys as
+            // well as values, and in quoted text that is entirely a reference
+            let value = if text.starts_with("@[") && text.ends_with(']') {
+                let expr = &text[2..text.len() - 1];
+                let mut variables = HashMap::new();
+                let mut failed = false;
+                for name in expression_names(expr) {
+                    match lookup(&name) {
+                        Some(Some(value)) => {
+                            variables.insert(name, value);
+                        }
+                        Some(None) => failed = true,
+                        None => {}
                     }
                 }
+
+                if failed {
+                    None
+                } else {
+                    match eval_expression(expr, &variables) {
+                        Ok(computed_value) => Some(computed_value),
+                        Err(e) => {
+                            diagnostics.push((i, e.to_string()));
+                            None
+                        }
+                    }
+                }
+            } else if let Some(name) = text.strip_prefix('@') {
+                lookup(name).flatten()
+            } else {
+                None
+            };
+
+            // Text that would otherwise be split apart by the parser stays
+            // quoted, as does text that was quoted to begin with
+            let quoted =
+                quoted || matches!(&value, Some(VariableValue::Text(x)) if needs_quotes(x));
+
+            // Parameters substituted into text that isn't otherwise interpolated
+            let value = value
+                .map(|x| x.to_string())
+                .or_else(|| (text != raw).then_some(text));
+            if let Some(value) = value {
+                let string_index = interpolated_strings.len();
+                interpolated_strings.push(value);
+                token_overrides.insert(i, string_index);
+                if quoted {
+                    quoted_overrides.insert(i);
+                }
             }
-            i += 1;
         }
 
+        let span = |token_index: usize| match &tokens[token_index] {
+            TextToken::Unquoted(scalar)
+            | TextToken::Quoted(scalar)
+            | TextToken::Parameter(scalar)
+            | TextToken::UndefinedParameter(scalar) => locate(source, scalar),
+            _ => None,
+        };
+        let location = |token_index: usize| {
+            let (file, data, offset) = source?.resolve(span(token_index)?.start);
+            Some((file, Location::new(data, offset, encoding)))
+        };
+
+        diagnostics.sort_by_key(|(token_index, _)| *token_index);
+        let errors: Vec<String> = diagnostics
+            .into_iter()
+            .map(|(token_index, message)| {
+                render_diagnostic(&message, source, span(token_index), encoding)
+            })
+            .collect();
+        if !errors.is_empty() && !collect_errors {
+            return Err(Box::new(InterpolationError {
+                diagnostics: errors,
+            }));
+        }
+
+        let warnings = warnings
+            .into_iter()
+            .map(|(token_index, message)| {
+                render_diagnostic(&message, source, span(token_index), encoding)
+            })
+            .collect();
+
+        let declared = declarations
+            .iter()
+            .map(|(&i, declaration)| {
+                let (file, location) = location(i).unzip();
+                VariableDeclaration {
+                    name: declaration.name.clone(),
+                    value: values.get(&i).cloned().flatten(),
+                    definition: declaration.definition.to_string(),
+                    location,
+                    file: file.flatten().map(String::from),
+                    root: scopes.block(i).is_none(),
+                    uses: uses.get(&i).copied().unwrap_or_default(),
+                }
+            })
+            .collect();
+
+        undefined.sort_by_key(|(token_index, _)| *token_index);
+        let undefined = undefined
+            .into_iter()
+            .map(|(i, name)| {
+                let (file, location) = location(i).unzip();
+                VariableReference {
+                    name,
+                    location,
+                    file: file.flatten().map(String::from),
+                }
+            })
+            .collect();
+
+        // Only variables declared at the root are visible to other files
+        let variables = scopes
+            .root_declarations()
+            .filter_map(|(name, x)| Some((name.to_owned(), values.get(&x).cloned().flatten()?)))
+            .collect();
+
         Ok(Self {
             original_tape: tape,
             interpolated_strings,
             token_overrides,
-            variable_declarations,
+            quoted_overrides,
+            declarations: declarations.into_keys().collect(),
+            parameter_blocks,
+            variables,
+            declared,
+            undefined,
+            errors,
+            warnings,
+            encoding,
+            preserve_operators: false,
         })
     }
 
+    /// The parameters (`$NAME$` and `[[NAME]`) left as written as no
+    /// parameters were given
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Every variable declared in the tape, including those within blocks,
+    /// in the order they are declared
+    pub fn variable_declarations(&self) -> &[VariableDeclaration] {
+        &self.declared
+    }
+
+    /// References to variables that are neither declared in the tape nor
+    /// defined outside of it
+    pub fn undefined_variables(&self) -> &[VariableReference] {
+        &self.undefined
+    }
+
+    /// The errors found while interpolating, only recorded when the tape is
+    /// interpolated with collected errors
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    /// The variables declared at the root of the tape and their values.
+    /// Variables declared within a block are not visible outside of it
+    pub fn declared_variables(&self) -> impl Iterator<Item = (&str, &VariableValue)> + '_ {
+        self.variables
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// Keep `==` and `?=` operators in the output instead of converting them
+    /// to assignments
+    pub fn with_preserved_operators(mut self, preserve: bool) -> Self {
+        self.preserve_operators = preserve;
+        self
+    }
+
     /// Materialize all tokens into a tape that owns its string data
     /// This allows using the full jomini API (JSON, readers, etc.)
     pub fn materialize(&self) -> MaterializedTape {
+        self.materialize_with(false)
+    }
+
+    /// Materialize the tokens with the text kept in the encoding of the
+    /// tape. Untouched tokens keep their original bytes, and when escaping,
+    /// quotes and backslashes of interpolated quoted text are escaped like
+    /// the original quoted text is
+    fn materialize_with(&self, escape_quoted: bool) -> MaterializedTape {
         let mut owned_strings = Vec::new();
-        let mut string_to_index: HashMap<String, usize> = HashMap::new();
+        let mut string_to_index: HashMap<Vec<u8>, usize> = HashMap::new();
         let mut token_data = Vec::new();
 
         // Helper function to get or create string index
-        let mut get_string_index = |s: String| -> usize {
+        let mut get_string_index = |s: Vec<u8>| -> usize {
             if let Some(&existing_index) = string_to_index.get(&s) {
                 existing_index
             } else {
@@ -202,36 +1130,46 @@ impl<'a> InterpolatedTape<'a> {
 
             // Check if this token has an interpolated override
             if let Some(&string_index) = self.token_overrides.get(&i) {
-                // Use interpolated value - force it to be Unquoted
+                // Use interpolated value
                 let interpolated_str = &self.interpolated_strings[string_index];
-                let owned_string_index = get_string_index(interpolated_str.clone());
-                token_data.push((TokenType::Unquoted, Some(owned_string_index)));
+                let quoted = self.quoted_overrides.contains(&i);
+                let interpolated_str = if quoted && escape_quoted {
+                    Cow::Owned(interpolated_str.replace('\\', "\\\\").replace('"', "\\\""))
+                } else {
+                    Cow::Borrowed(interpolated_str.as_str())
+                };
+                let bytes = match self.encoding {
+                    Encoding::Utf8 => interpolated_str.into_owned().into_bytes(),
+                    Encoding::Windows1252 => encode_windows1252(&interpolated_str),
+                };
+                let owned_string_index = get_string_index(bytes);
+                let token_type = if quoted {
+                    TokenType::Quoted
+                } else {
+                    TokenType::Unquoted
+                };
+                token_data.push((token_type, Some(owned_string_index)));
             } else {
                 // Use original token
                 match original_token {
                     TextToken::Unquoted(scalar) => {
-                        let text = String::from_utf8_lossy(scalar.as_bytes()).into_owned();
-                        let owned_string_index = get_string_index(text);
+                        let owned_string_index = get_string_index(scalar.as_bytes().to_vec());
                         token_data.push((TokenType::Unquoted, Some(owned_string_index)));
                     }
                     TextToken::Quoted(scalar) => {
-                        let text = String::from_utf8_lossy(scalar.as_bytes()).into_owned();
-                        let owned_string_index = get_string_index(text);
+                        let owned_string_index = get_string_index(scalar.as_bytes().to_vec());
                         token_data.push((TokenType::Quoted, Some(owned_string_index)));
                     }
                     TextToken::Header(scalar) => {
-                        let text = String::from_utf8_lossy(scalar.as_bytes()).into_owned();
-                        let owned_string_index = get_string_index(text);
+                        let owned_string_index = get_string_index(scalar.as_bytes().to_vec());
                         token_data.push((TokenType::Header, Some(owned_string_index)));
                     }
                     TextToken::Parameter(scalar) => {
-                        let text = String::from_utf8_lossy(scalar.as_bytes()).into_owned();
-                        let owned_string_index = get_string_index(text);
+                        let owned_string_index = get_string_index(scalar.as_bytes().to_vec());
                         token_data.push((TokenType::Parameter, Some(owned_string_index)));
                     }
                     TextToken::UndefinedParameter(scalar) => {
-                        let text = String::from_utf8_lossy(scalar.as_bytes()).into_owned();
-                        let owned_string_index = get_string_index(text);
+                        let owned_string_index = get_string_index(scalar.as_bytes().to_vec());
                         token_data.push((TokenType::UndefinedParameter, Some(owned_string_index)));
                     }
                     TextToken::Array { end, mixed } => {
@@ -271,6 +1209,17 @@ impl<'a> InterpolatedTape<'a> {
         }
     }
 
+    /// Write the tape back out as game script, in the encoding it was
+    /// interpolated with, with variable declarations removed and references
+    /// replaced by their values. Operators are kept as written
+    pub fn to_script_writer<W: std::io::Write>(&self, writer: W) -> std::io::Result<()> {
+        let materialized = self.materialize_with(true);
+        let tokens =
+            materialized.create_filtered_tokens(&self.declarations, &self.parameter_blocks, true);
+        let mut writer = ScriptTokenWriter { writer, depth: 0 };
+        writer.write_entries(&tokens, 0, tokens.len())
+    }
+
     /// Write JSON output with filtering directly to a writer with options
     pub fn to_writer_with_options<W: std::io::Write>(
         &self,
@@ -280,7 +1229,11 @@ impl<'a> InterpolatedTape<'a> {
     ) -> std::io::Result<()> {
         // Create filtered tokens that exclude variable declarations
         let materialized = self.materialize();
-        let filtered_tokens = materialized.create_filtered_tokens(&self.variable_declarations);
+        let filtered_tokens = materialized.create_filtered_tokens(
+            &self.declarations,
+            &self.parameter_blocks,
+            self.preserve_operators,
+        );
 
         // Use jomini's built-in JSON serialization with proper options
         match encoding {
@@ -297,10 +1250,171 @@ impl<'a> InterpolatedTape<'a> {
     }
 }
 
+/// Writes a token stream, whose text is already in the output encoding, out
+/// as game script
+struct ScriptTokenWriter<W> {
+    writer: W,
+    depth: usize,
+}
+
+impl<W: std::io::Write> ScriptTokenWriter<W> {
+    /// Write the key value pairs of an object found between the token indices
+    fn write_entries(
+        &mut self,
+        tokens: &[TextToken],
+        mut i: usize,
+        end: usize,
+    ) -> std::io::Result<()> {
+        while i < end {
+            self.write_indent()?;
+            if let (
+                TextToken::Parameter(_) | TextToken::UndefinedParameter(_),
+                Some(TextToken::Object { end: close, .. } | TextToken::Array { end: close, .. }),
+            ) = (&tokens[i], tokens.get(i + 1))
+            {
+                i = self.write_parameter_block(tokens, i, *close)?;
+                self.write_raw(b"\n")?;
+                continue;
+            }
+
+            i = self.write_token(tokens, i)?;
+            if let Some(TextToken::Operator(op)) = tokens.get(i) {
+                self.write_raw(operator_symbol(*op).as_bytes())?;
+                i += 1;
+            } else {
+                self.write_raw(b"=")?;
+            }
+
+            i = self.write_token(tokens, i)?;
+            self.write_raw(b"\n")?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a `[[NAME] ... ]` block whose contents are the container that
+    /// follows the parameter and ends at the given index
+    fn write_parameter_block(
+        &mut self,
+        tokens: &[TextToken],
+        i: usize,
+        end: usize,
+    ) -> std::io::Result<usize> {
+        self.write_token(tokens, i)?;
+        if matches!(tokens[i + 1], TextToken::Object { .. }) {
+            self.write_raw(b"\n")?;
+            self.depth += 1;
+            self.write_entries(tokens, i + 2, end)?;
+            self.depth -= 1;
+            self.write_indent()?;
+        } else {
+            let mut j = i + 2;
+            while j < end {
+                self.write_raw(b" ")?;
+                j = self.write_token(tokens, j)?;
+            }
+            self.write_raw(b" ")?;
+        }
+
+        self.write_raw(b"]")?;
+        Ok(end + 1)
+    }
+
+    /// Write the token at the index and return the index of the next token
+    fn write_token(&mut self, tokens: &[TextToken], i: usize) -> std::io::Result<usize> {
+        match &tokens[i] {
+            TextToken::Object { end, .. } => {
+                self.write_raw(b"{\n")?;
+                self.depth += 1;
+                self.write_entries(tokens, i + 1, *end)?;
+                self.depth -= 1;
+                self.write_indent()?;
+                self.write_raw(b"}")?;
+                Ok(end + 1)
+            }
+            TextToken::Array { end, .. } => {
+                self.write_raw(b"{")?;
+                let mut j = i + 1;
+                while j < *end {
+                    if matches!(tokens[j], TextToken::MixedContainer) {
+                        j += 1;
+                        continue;
+                    }
+
+                    self.write_raw(b" ")?;
+                    j = self.write_token(tokens, j)?;
+
+                    // Key value pairs within mixed containers (`{ a b=c }`)
+                    if let Some(TextToken::Operator(op)) = tokens.get(j).filter(|_| j < *end) {
+                        self.write_raw(operator_symbol(*op).as_bytes())?;
+                        j = self.write_token(tokens, j + 1)?;
+                    }
+                }
+
+                self.write_raw(b" }")?;
+                Ok(end + 1)
+            }
+            TextToken::Header(scalar) => {
+                self.write_raw(scalar.as_bytes())?;
+                self.write_raw(b" ")?;
+                self.write_token(tokens, i + 1)
+            }
+            TextToken::Unquoted(scalar) => {
+                self.write_raw(scalar.as_bytes())?;
+                Ok(i + 1)
+            }
+            TextToken::Quoted(scalar) => {
+                self.write_raw(b"\"")?;
+                self.write_raw(scalar.as_bytes())?;
+                self.write_raw(b"\"")?;
+                Ok(i + 1)
+            }
+            TextToken::Parameter(scalar) => {
+                self.write_raw(b"[[")?;
+                self.write_raw(scalar.as_bytes())?;
+                self.write_raw(b"]")?;
+                Ok(i + 1)
+            }
+            TextToken::UndefinedParameter(scalar) => {
+                self.write_raw(b"[[!")?;
+                self.write_raw(scalar.as_bytes())?;
+                self.write_raw(b"]")?;
+                Ok(i + 1)
+            }
+            _ => Ok(i + 1),
+        }
+    }
+
+    fn write_indent(&mut self) -> std::io::Result<()> {
+        for _ in 0..self.depth {
+            self.writer.write_all(b"\t")?;
+        }
+        Ok(())
+    }
+
+    fn write_raw(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(data)
+    }
+}
+
+fn operator_symbol(op: jomini::text::Operator) -> &'static str {
+    match op {
+        jomini::text::Operator::LessThan => "<",
+        jomini::text::Operator::LessThanEqual => "<=",
+        jomini::text::Operator::GreaterThan => ">",
+        jomini::text::Operator::GreaterThanEqual => ">=",
+        jomini::text::Operator::NotEqual => "!=",
+        jomini::text::Operator::Equal => "=",
+        jomini::text::Operator::Exact => "==",
+        jomini::text::Operator::Exists => "?=",
+    }
+}
+
 /// A materialized tape that owns all string data and provides token access
 pub struct MaterializedTape {
-    /// Owns all string data (both original and interpolated)
-    owned_strings: Vec<String>,
+    /// Owns all string data (both original and interpolated) in the encoding
+    /// of the tape
+    owned_strings: Vec<Vec<u8>>,
     /// Token data without string references - we recreate tokens on demand
     token_data: Vec<(TokenType, Option<usize>)>, // (token_type, string_index)
 }
@@ -327,19 +1441,19 @@ impl MaterializedTape {
         for (token_type, string_index_opt) in &self.token_data {
             let token = match (token_type, string_index_opt) {
                 (TokenType::Unquoted, Some(idx)) => {
-                    TextToken::Unquoted(Scalar::new(self.owned_strings[*idx].as_bytes()))
+                    TextToken::Unquoted(Scalar::new(&self.owned_strings[*idx]))
                 }
                 (TokenType::Quoted, Some(idx)) => {
-                    TextToken::Quoted(Scalar::new(self.owned_strings[*idx].as_bytes()))
+                    TextToken::Quoted(Scalar::new(&self.owned_strings[*idx]))
                 }
                 (TokenType::Header, Some(idx)) => {
-                    TextToken::Header(Scalar::new(self.owned_strings[*idx].as_bytes()))
+                    TextToken::Header(Scalar::new(&self.owned_strings[*idx]))
                 }
                 (TokenType::Parameter, Some(idx)) => {
-                    TextToken::Parameter(Scalar::new(self.owned_strings[*idx].as_bytes()))
+                    TextToken::Parameter(Scalar::new(&self.owned_strings[*idx]))
                 }
                 (TokenType::UndefinedParameter, Some(idx)) => {
-                    TextToken::UndefinedParameter(Scalar::new(self.owned_strings[*idx].as_bytes()))
+                    TextToken::UndefinedParameter(Scalar::new(&self.owned_strings[*idx]))
                 }
                 (TokenType::Array { end, mixed }, None) => TextToken::Array {
                     end: *end,
@@ -360,13 +1474,21 @@ impl MaterializedTape {
         tokens
     }
 
-    /// Create filtered tokens that exclude variable declarations
+    /// Create filtered tokens that exclude the variable declarations at the
+    /// given token indices and expand or drop the given parameter blocks
     pub fn create_filtered_tokens(
         &self,
-        variable_declarations: &std::collections::HashSet<String>,
+        declarations: &HashSet<usize>,
+        parameter_blocks: &HashMap<usize, bool>,
+        preserve_operators: bool,
     ) -> Vec<TextToken<'_>> {
         let original_tokens = self.create_tokens();
-        TokenFilter::filter_tokens_static(&original_tokens, variable_declarations)
+        TokenFilter::filter_tokens_static(
+            &original_tokens,
+            declarations,
+            parameter_blocks,
+            preserve_operators,
+        )
     }
 }
 
@@ -377,15 +1499,38 @@ impl TokenFilter {
     /// Filter tokens to remove variable declarations while maintaining token stream integrity
     fn filter_tokens_static<'a>(
         tokens: &[TextToken<'a>],
-        variable_declarations: &std::collections::HashSet<String>,
+        declarations: &HashSet<usize>,
+        parameter_blocks: &HashMap<usize, bool>,
+        preserve_operators: bool,
     ) -> Vec<TextToken<'a>> {
         let mut filtered_tokens = Vec::new();
         let mut index_mapping = std::collections::HashMap::new(); // original_index -> filtered_index
+        let mut expanded_ends = HashSet::new(); // end tokens of expanded parameter blocks
 
         // First pass: collect all indices that should be kept
         let mut i = 0;
         while i < tokens.len() {
-            let should_skip = Self::should_skip_token_sequence(tokens, i, variable_declarations);
+            if expanded_ends.contains(&i) {
+                i += 1;
+                continue;
+            }
+
+            // Parameter blocks are either dropped or replaced by their contents
+            if let (
+                Some(&keep),
+                Some(TextToken::Object { end, .. } | TextToken::Array { end, .. }),
+            ) = (parameter_blocks.get(&i), tokens.get(i + 1))
+            {
+                if keep {
+                    expanded_ends.insert(*end);
+                    i += 2;
+                } else {
+                    i = end + 1;
+                }
+                continue;
+            }
+
+            let should_skip = Self::should_skip_token_sequence(tokens, i, declarations);
 
             if should_skip.skip {
                 // Skip the entire sequence (key + operator + value)
@@ -399,10 +1544,12 @@ impl TokenFilter {
             // Convert EXACT and EXISTS operators to regular assignment
             let mut token = tokens[i].clone();
             if let TextToken::Operator(op) = &token {
-                if matches!(
-                    op,
-                    jomini::text::Operator::Exact | jomini::text::Operator::Exists
-                ) {
+                if !preserve_operators
+                    && matches!(
+                        op,
+                        jomini::text::Operator::Exact | jomini::text::Operator::Exists
+                    )
+                {
                     token = TextToken::Operator(jomini::text::Operator::Equal);
                 }
             }
@@ -423,12 +1570,12 @@ impl TokenFilter {
     fn should_skip_token_sequence(
         tokens: &[TextToken<'_>],
         start_index: usize,
-        variable_declarations: &std::collections::HashSet<String>,
+        declarations: &HashSet<usize>,
     ) -> SkipResult {
-        if let Some(TextToken::Unquoted(scalar)) = tokens.get(start_index) {
-            let text = String::from_utf8_lossy(scalar.as_bytes());
-
-            if variable_declarations.contains(&*text) {
+        // Declarations are identified by position rather than name, as a
+        // variable declared in one block may be shadowed in another
+        if let Some(TextToken::Unquoted(_)) = tokens.get(start_index) {
+            if declarations.contains(&start_index) {
                 // This is a variable declaration - calculate how many tokens to skip
                 let mut skip_to = start_index + 1; // Skip the key
 
@@ -497,6 +1644,68 @@ struct SkipResult {
     next_index: usize,
 }
 
+/// Replace each `$NAME$` in the text with the value of the parameter
+fn substitute_params(text: &str, params: &HashMap<String, String>) -> Result<String, String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        let Some(len) = rest[start + 1..].find('$') else {
+            break;
+        };
+
+        let name = &rest[start + 1..start + 1 + len];
+        let value = params
+            .get(name)
+            .ok_or_else(|| format!("Undefined parameter: ${}$", name))?;
+        result.push_str(&rest[..start]);
+        result.push_str(value);
+        rest = &rest[start + len + 2..];
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// The names of the `$NAME$` parameters within text
+fn parameter_names(text: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        let Some(len) = rest[start + 1..].find('$') else {
+            break;
+        };
+
+        names.push(&rest[start + 1..start + 1 + len]);
+        rest = &rest[start + len + 2..];
+    }
+
+    names
+}
+
+/// The variables referenced by an expression
+fn expression_names(expr: &str) -> Vec<String> {
+    let tokens = tokenize_expression(expr).unwrap_or_default();
+    tokens
+        .iter()
+        .enumerate()
+        .filter_map(|(i, token)| match token {
+            ExprToken::Ident(name) if tokens.get(i + 1) != Some(&ExprToken::OpenParen) => {
+                Some(name.clone())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether text substituted for a token must be quoted for the parser to
+/// read it back as a single scalar
+fn needs_quotes(text: &str) -> bool {
+    text.is_empty()
+        || text.chars().any(|c| {
+            c.is_whitespace() || c.is_control() || matches!(c, '=' | '{' | '}' | '"' | '#')
+        })
+}
+
 /// Format a numeric value as a string
 fn format_numeric_value(value: f64) -> String {
     if value.fract() == 0.0 {
@@ -513,10 +1722,16 @@ fn parse_f64(s: &[u8]) -> Result<f64, Box<dyn std::error::Error>> {
 
 fn eval_expression(
     expr: &str,
-    variables: &HashMap<String, f64>,
-) -> Result<f64, Box<dyn std::error::Error>> {
-    // Enhanced expression evaluator with proper parentheses and operator precedence
-    // Handles: numbers, variables, +, -, *, /, parentheses with proper precedence
+    variables: &HashMap<String, VariableValue>,
+) -> Result<VariableValue, Box<dyn std::error::Error>> {
+    // Recursive descent evaluator over the tokens of the expression. From
+    // lowest to highest precedence:
+    //  - addition and subtraction: `a + b`, `a - b`. Adding text
+    //    concatenates: `"gfx/" + name + ".dds"`
+    //  - multiplication, division, and modulo: `a * b`, `a / b`, `a % b`
+    //  - unary minus: `-a`
+    //  - exponentiation (right associative): `a ^ b`
+    //  - numbers, text, variables, function calls (`min(a, b)`), and parentheses
     let expr = expr.trim();
 
     // Remove outer brackets if present
@@ -526,145 +1741,275 @@ fn eval_expression(
         expr
     };
 
-    eval_addition_subtraction(expr, variables)
+    let mut parser = ExpressionParser {
+        tokens: tokenize_expression(expr)?,
+        pos: 0,
+        variables,
+    };
+
+    let value = parser.parse_additive()?;
+    match parser.peek() {
+        None => Ok(value),
+        Some(token) => Err(format!("Unexpected {} in expression: {}", token, expr).into()),
+    }
 }
 
-fn contains_additive_ops_at_depth_zero(s: &str) -> bool {
-    let mut depth = 0;
-    for c in s.chars() {
-        match c {
-            '(' => depth += 1,
-            ')' => depth -= 1,
-            '+' | '-' if depth == 0 => return true,
-            _ => {}
+#[derive(Debug, Clone, PartialEq)]
+enum ExprToken {
+    Number(f64),
+    Text(String),
+    Ident(String),
+    Op(char),
+    OpenParen,
+    CloseParen,
+    Comma,
+}
+
+impl std::fmt::Display for ExprToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExprToken::Number(x) => write!(f, "number `{}`", x),
+            ExprToken::Text(x) => write!(f, "text \"{}\"", x),
+            ExprToken::Ident(x) => write!(f, "`{}`", x),
+            ExprToken::Op(x) => write!(f, "operator `{}`", x),
+            ExprToken::OpenParen => write!(f, "`(`"),
+            ExprToken::CloseParen => write!(f, "`)`"),
+            ExprToken::Comma => write!(f, "`,`"),
         }
     }
-    false
 }
 
-fn eval_addition_subtraction(
-    expr: &str,
-    variables: &HashMap<String, f64>,
-) -> Result<f64, Box<dyn std::error::Error>> {
-    // Handle + and - operations (lowest precedence)
-    // For left-associativity with recursive descent, find the RIGHTMOST operator
-    let expr = expr.trim();
+fn tokenize_expression(expr: &str) -> Result<Vec<ExprToken>, Box<dyn std::error::Error>> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '+' | '-' | '*' | '/' | '%' | '^' => tokens.push(ExprToken::Op(c)),
+            '(' => tokens.push(ExprToken::OpenParen),
+            ')' => tokens.push(ExprToken::CloseParen),
+            ',' => tokens.push(ExprToken::Comma),
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => text.extend(chars.next().map(|(_, c)| c)),
+                        Some((_, c)) => text.push(c),
+                        None => {
+                            return Err(format!("Unterminated text in expression: {}", expr).into())
+                        }
+                    }
+                }
+                tokens.push(ExprToken::Text(text));
+            }
+            c if c.is_alphanumeric() || matches!(c, '_' | '.' | '@') => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) =
+                    chars.next_if(|(_, c)| c.is_alphanumeric() || matches!(c, '_' | '.'))
+                {
+                    end = i + c.len_utf8();
+                }
 
-    // Handle negative numbers at the start (but only if remainder has no +/- at depth 0)
-    if expr.starts_with('-')
-        && !expr[1..].starts_with('(')
-        && !contains_additive_ops_at_depth_zero(&expr[1..])
-    {
-        let operand = &expr[1..];
-        return Ok(-eval_multiplication_division(operand, variables)?);
-    }
-
-    // Find + or - operators that are not inside parentheses, scanning right to left
-    let mut paren_depth = 0;
-    let chars: Vec<char> = expr.chars().collect();
-
-    for i in (0..chars.len()).rev() {
-        match chars[i] {
-            ')' => paren_depth += 1,
-            '(' => paren_depth -= 1,
-            '+' | '-' if paren_depth == 0 && i > 0 => {
-                let left_part = &expr[..i].trim();
-                let right_part = &expr[i + 1..].trim();
-                let left_val = eval_addition_subtraction(left_part, variables)?;
-                let right_val = eval_multiplication_division(right_part, variables)?;
-
-                return Ok(if chars[i] == '+' {
-                    left_val + right_val
+                let word = &expr[start..end];
+                if word.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+                    let num = word
+                        .parse::<f64>()
+                        .map_err(|_| format!("Invalid number: {}", word))?;
+                    tokens.push(ExprToken::Number(num));
                 } else {
-                    left_val - right_val
-                });
+                    let name = word.strip_prefix('@').unwrap_or(word);
+                    tokens.push(ExprToken::Ident(name.to_string()));
+                }
+            }
+            _ => {
+                return Err(format!("Unexpected character `{}` in expression: {}", c, expr).into())
             }
-            _ => {}
         }
     }
 
-    eval_multiplication_division(expr, variables)
+    Ok(tokens)
 }
 
-fn eval_multiplication_division(
-    expr: &str,
-    variables: &HashMap<String, f64>,
-) -> Result<f64, Box<dyn std::error::Error>> {
-    // Handle * and / operations (higher precedence)
-    // For left-associativity with recursive descent, find the RIGHTMOST operator
-    let expr = expr.trim();
+struct ExpressionParser<'a> {
+    tokens: Vec<ExprToken>,
+    pos: usize,
+    variables: &'a HashMap<String, VariableValue>,
+}
 
-    // Find * or / operators that are not inside parentheses, scanning right to left
-    let mut paren_depth = 0;
-    let chars: Vec<char> = expr.chars().collect();
-
-    for i in (0..chars.len()).rev() {
-        match chars[i] {
-            ')' => paren_depth += 1,
-            '(' => paren_depth -= 1,
-            '*' | '/' if paren_depth == 0 && i > 0 => {
-                let left_part = &expr[..i].trim();
-                let right_part = &expr[i + 1..].trim();
-                let left_val = eval_multiplication_division(left_part, variables)?;
-                let right_val = eval_factor(right_part, variables)?;
-
-                return Ok(if chars[i] == '*' {
-                    left_val * right_val
-                } else {
-                    left_val / right_val
-                });
+impl ExpressionParser<'_> {
+    fn peek(&self) -> Option<&ExprToken> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<ExprToken> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn next_op(&mut self, ops: &[char]) -> Option<char> {
+        match self.peek() {
+            Some(ExprToken::Op(op)) if ops.contains(op) => {
+                let op = *op;
+                self.pos += 1;
+                Some(op)
             }
-            _ => {}
+            _ => None,
         }
     }
 
-    eval_factor(expr, variables)
-}
+    fn parse_additive(&mut self) -> Result<VariableValue, Box<dyn std::error::Error>> {
+        let mut value = self.parse_multiplicative()?;
+        while let Some(op) = self.next_op(&['+', '-']) {
+            let rhs = self.parse_multiplicative()?;
+            value = match (op, value, rhs) {
+                // Numbers are written as they would be in the output
+                ('+', lhs @ VariableValue::Text(_), rhs)
+                | ('+', lhs, rhs @ VariableValue::Text(_)) => {
+                    VariableValue::Text(format!("{}{}", lhs, rhs))
+                }
+                ('+', lhs, rhs) => VariableValue::Number(lhs.to_number()? + rhs.to_number()?),
+                (_, lhs, rhs) => VariableValue::Number(lhs.to_number()? - rhs.to_number()?),
+            };
+        }
+        Ok(value)
+    }
 
-fn eval_factor(
-    expr: &str,
-    variables: &HashMap<String, f64>,
-) -> Result<f64, Box<dyn std::error::Error>> {
-    // Handle parentheses and basic operands (highest precedence)
-    let expr = expr.trim();
+    fn parse_multiplicative(&mut self) -> Result<VariableValue, Box<dyn std::error::Error>> {
+        let mut value = self.parse_unary()?;
+        while let Some(op) = self.next_op(&['*', '/', '%']) {
+            let (lhs, rhs) = (value.to_number()?, self.parse_unary()?.to_number()?);
+            value = VariableValue::Number(match op {
+                '*' => lhs * rhs,
+                _ if rhs == 0.0 => {
+                    let name = if op == '/' { "Division" } else { "Modulo" };
+                    return Err(format!("{} by zero", name).into());
+                }
+                '/' => lhs / rhs,
+                _ => lhs % rhs,
+            });
+        }
+        Ok(value)
+    }
 
-    // Handle parenthesized expressions
-    if expr.starts_with('(') && expr.ends_with(')') {
-        let inner = &expr[1..expr.len() - 1];
-        return eval_addition_subtraction(inner, variables);
+    fn parse_unary(&mut self) -> Result<VariableValue, Box<dyn std::error::Error>> {
+        match self.next_op(&['-', '+']) {
+            Some('-') => Ok(VariableValue::Number(-self.parse_unary()?.to_number()?)),
+            Some(_) => self.parse_unary(),
+            None => self.parse_power(),
+        }
     }
 
-    // Handle negative expressions with parentheses
-    if expr.starts_with('-') && expr[1..].starts_with('(') && expr.ends_with(')') {
-        let inner = &expr[2..expr.len() - 1];
-        return Ok(-eval_addition_subtraction(inner, variables)?);
+    fn parse_power(&mut self) -> Result<VariableValue, Box<dyn std::error::Error>> {
+        let base = self.parse_primary()?;
+        if self.next_op(&['^']).is_some() {
+            // Right associative and binds tighter than a unary minus on its
+            // left, so `-2^2` is -4 and `2^-1` is 0.5
+            let exponent = self.parse_unary()?.to_number()?;
+            Ok(VariableValue::Number(base.to_number()?.powf(exponent)))
+        } else {
+            Ok(base)
+        }
     }
 
-    eval_simple_operand(expr, variables)
-}
+    fn parse_primary(&mut self) -> Result<VariableValue, Box<dyn std::error::Error>> {
+        match self.next() {
+            Some(ExprToken::Number(x)) => Ok(VariableValue::Number(x)),
+            Some(ExprToken::Text(x)) => Ok(VariableValue::Text(x)),
+            Some(ExprToken::OpenParen) => {
+                let value = self.parse_additive()?;
+                self.expect_close_paren()?;
+                Ok(value)
+            }
+            Some(ExprToken::Ident(name)) if self.peek() == Some(&ExprToken::OpenParen) => {
+                self.pos += 1;
+                let args = self.parse_arguments()?;
+                let args = args
+                    .iter()
+                    .map(VariableValue::to_number)
+                    .collect::<Result<Vec<_>, _>>()?;
+                call_function(&name, &args).map(VariableValue::Number)
+            }
+            Some(ExprToken::Ident(name)) => match self.variables.get(&name) {
+                Some(value) => Ok(value.clone()),
+                None => Err(format!("Unknown operand: {}", name).into()),
+            },
+            Some(token) => Err(format!("Unexpected {} in expression", token).into()),
+            None => Err("Unexpected end of expression".into()),
+        }
+    }
 
-fn eval_simple_operand(
-    operand: &str,
-    variables: &HashMap<String, f64>,
-) -> Result<f64, Box<dyn std::error::Error>> {
-    let operand = operand.trim();
+    fn parse_arguments(&mut self) -> Result<Vec<VariableValue>, Box<dyn std::error::Error>> {
+        let mut args = Vec::new();
+        if self.peek() == Some(&ExprToken::CloseParen) {
+            self.pos += 1;
+            return Ok(args);
+        }
 
-    if let Some(&value) = variables.get(operand) {
-        return Ok(value);
+        loop {
+            args.push(self.parse_additive()?);
+            match self.next() {
+                Some(ExprToken::Comma) => {}
+                Some(ExprToken::CloseParen) => return Ok(args),
+                Some(token) => return Err(format!("Expected `,` or `)`, found {}", token).into()),
+                None => return Err("Missing closing parenthesis".into()),
+            }
+        }
     }
 
-    if let Ok(num) = operand.parse::<f64>() {
-        return Ok(num);
+    fn expect_close_paren(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.next() {
+            Some(ExprToken::CloseParen) => Ok(()),
+            Some(token) => Err(format!("Expected `)`, found {}", token).into()),
+            None => Err("Missing closing parenthesis".into()),
+        }
     }
+}
 
-    // Handle negative variables
-    if let Some(var_name) = operand.strip_prefix('-') {
-        if let Some(&value) = variables.get(var_name) {
-            return Ok(-value);
+fn call_function(name: &str, args: &[f64]) -> Result<f64, Box<dyn std::error::Error>> {
+    let arity = |expected: usize| -> Result<(), Box<dyn std::error::Error>> {
+        if args.len() == expected {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} expects {} argument{}, got {}",
+                name,
+                expected,
+                if expected == 1 { "" } else { "s" },
+                args.len()
+            )
+            .into())
         }
-    }
+    };
+
+    match name {
+        "min" | "max" => {
+            if args.len() < 2 {
+                return Err(
+                    format!("{} expects at least 2 arguments, got {}", name, args.len()).into(),
+                );
+            }
 
-    Err(format!("Unknown operand: {}", operand).into())
+            let fold = if name == "min" { f64::min } else { f64::max };
+            Ok(args[1..].iter().copied().fold(args[0], fold))
+        }
+        "abs" => arity(1).map(|_| args[0].abs()),
+        "floor" => arity(1).map(|_| args[0].floor()),
+        "ceil" => arity(1).map(|_| args[0].ceil()),
+        "round" => arity(1).map(|_| args[0].round()),
+        "clamp" => {
+            arity(3)?;
+            let (value, low, high) = (args[0], args[1], args[2]);
+            if low > high {
+                return Err(
+                    format!("clamp lower bound {} exceeds upper bound {}", low, high).into(),
+                );
+            }
+            Ok(value.max(low).min(high))
+        }
+        _ => Err(format!("Unknown function: {}", name).into()),
+    }
 }
 
 #[cfg(test)]
@@ -782,6 +2127,29 @@ scale = @[1-0.25]
         Ok(())
     }
 
+    #[test]
+    fn test_block_scoped_variables() -> Result<(), Box<dyn std::error::Error>> {
+        let data = br#"
+@x = 1
+a = { @x = 2 b = @x c = @[x * 10] }
+d = @x
+e = { f = @x }
+"#;
+
+        let tape = TextTape::from_slice(data)?;
+        let interpolated_tape =
+            InterpolatedTape::from_tape_with_interpolation(&tape, Encoding::Utf8)?;
+
+        let json_output = interpolated_tape.to_json();
+        let expected_json = r#"{"a":{"b":2,"c":20},"d":1,"e":{"f":1}}"#;
+        assert_eq!(json_output, expected_json);
+
+        let declared: Vec<_> = interpolated_tape.declared_variables().collect();
+        assert_eq!(declared, vec![("x", &VariableValue::Number(1.0))]);
+
+        Ok(())
+    }
+
     #[test]
     fn test_comprehensive_interpolation_with_complete_json(
     ) -> Result<(), Box<dyn std::error::Error>> {
@@ -897,6 +2265,193 @@ test_eq = @[ width ]
         Ok(())
     }
 
+    #[test]
+    fn test_preserved_comparison_operators() -> Result<(), Box<dyn std::error::Error>> {
+        let data = br#"
+@limit = 42
+test_exact == @limit
+test_exists ?= @[ limit + 1 ]
+test_eq = @limit
+"#;
+
+        let tape = TextTape::from_slice(data)?;
+        let interpolated_tape =
+            InterpolatedTape::from_tape_with_interpolation(&tape, Encoding::Utf8)?
+                .with_preserved_operators(true);
+        let json_output = interpolated_tape.to_json();
+
+        let expected_json =
+            r#"{"test_exact":{"EXACT":42},"test_exists":{"EXISTS":43},"test_eq":42}"#;
+        assert_eq!(json_output, expected_json);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_math_functions_and_operators() -> Result<(), Box<dyn std::error::Error>> {
+        let data = br#"
+@base = 7
+@limit = @[ clamp(base * 3, 0, 16) ]
+test_mod = @[ base % 4 ]
+test_pow = @[ 2 ^ 3 ^ 2 ]
+test_neg_pow = @[ -2^2 ]
+test_precedence = @[ 1 + 2 * 3 ^ 2 % 5 ]
+test_min = @[ min(base, 3, 5) ]
+test_max = @[ max(base, limit) ]
+test_abs = @[ abs(3 - base) ]
+test_round = @[ round(base / 2) + floor(2.7) + ceil(2.1) ]
+test_limit = @limit
+"#;
+
+        let tape = TextTape::from_slice(data)?;
+        let interpolated_tape =
+            InterpolatedTape::from_tape_with_interpolation(&tape, Encoding::Utf8)?;
+
+        let json_output = interpolated_tape.to_json();
+        let expected_json = r#"{"test_mod":3,"test_pow":512,"test_neg_pow":-4,"test_precedence":4,"test_min":3,"test_max":16,"test_abs":4,"test_round":9,"test_limit":16}"#;
+        assert_eq!(json_output, expected_json);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expression_errors() {
+        let variables = HashMap::new();
+        let error = |expr| eval_expression(expr, &variables).unwrap_err().to_string();
+
+        assert_eq!(error("1 / 0"), "Division by zero");
+        assert_eq!(error("5 % (2 - 2)"), "Modulo by zero");
+        assert_eq!(error("abs(1, 2)"), "abs expects 1 argument, got 2");
+        assert_eq!(error("clamp(1, 2)"), "clamp expects 3 arguments, got 2");
+        assert_eq!(error("min(1)"), "min expects at least 2 arguments, got 1");
+        assert_eq!(error("sqrt(4)"), "Unknown function: sqrt");
+        assert_eq!(error("(1 + 2"), "Missing closing parenthesis");
+        assert_eq!(error("missing * 2"), "Unknown operand: missing");
+    }
+
+    #[test]
+    fn test_interpolation_with_external_variables() -> Result<(), Box<dyn std::error::Error>> {
+        let data = br#"
+@local = @[ shared * 2 ]
+@shared = 3
+value = @[ shared + local ]
+"#;
+
+        let mut variables = HashMap::new();
+        variables.insert(String::from("shared"), 10.0);
+
+        let tape = TextTape::from_slice(data)?;
+        let options = InterpolationOptions::new(Encoding::Utf8).with_variables(variables);
+        let interpolated_tape = InterpolatedTape::from_tape_with_options(&tape, options)?;
+
+        // The external value is used until the file redefines it
+        assert_eq!(interpolated_tape.to_json(), r#"{"value":23}"#);
+
+        let mut declared: Vec<_> = interpolated_tape.declared_variables().collect();
+        declared.sort_by(|a, b| a.0.cmp(b.0));
+        let (local, shared) = (VariableValue::Number(20.0), VariableValue::Number(3.0));
+        assert_eq!(declared, vec![("local", &local), ("shared", &shared)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpolation_to_script() -> Result<(), Box<dyn std::error::Error>> {
+        let data = br#"
+@scale = 10
+obj = {
+  x = @[scale * 2]
+  y > @scale
+  list = { 1 @scale }
+  color = rgb { 1 2 3 }
+  title = "A \"quoted\" name"
+}
+flag ?= yes
+"#;
+
+        let tape = TextTape::from_slice(data)?;
+        let interpolated_tape =
+            InterpolatedTape::from_tape_with_interpolation(&tape, Encoding::Utf8)?;
+
+        let mut output = Vec::new();
+        interpolated_tape.to_script_writer(&mut output)?;
+        let expected = "obj={\n\tx=20\n\ty>10\n\tlist={ 1 10 }\n\tcolor=rgb { 1 2 3 }\n\ttitle=\"A \\\"quoted\\\" name\"\n}\nflag?=yes\n";
+        assert_eq!(String::from_utf8(output)?, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parameters() -> Result<(), Box<dyn std::error::Error>> {
+        let data = br#"
+effect = {
+  add_gold = $amount$
+  [[bonus] add_prestige = $bonus$ ]
+  [[!bonus] add_piety = 1 ]
+  name = "$title$_desc"
+}
+"#;
+
+        let tape = TextTape::from_slice(data)?;
+        let interpolate = |params: &[(&str, &str)]| {
+            let params = params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let options = InterpolationOptions::new(Encoding::Utf8).with_params(params);
+            InterpolatedTape::from_tape_with_options(&tape, options).map(|x| x.to_json())
+        };
+
+        assert_eq!(
+            interpolate(&[("amount", "50"), ("title", "duke")])?,
+            r#"{"effect":{"add_gold":50,"add_piety":1,"name":"duke_desc"}}"#
+        );
+        assert_eq!(
+            interpolate(&[("amount", "50"), ("title", "duke"), ("bonus", "5")])?,
+            r#"{"effect":{"add_gold":50,"add_prestige":5,"name":"duke_desc"}}"#
+        );
+
+        let error = interpolate(&[("title", "duke")]).unwrap_err().to_string();
+        assert_eq!(error, "Undefined parameter: $amount$");
+
+        // Without parameters, blocks are written back as they were
+        let interpolated_tape =
+            InterpolatedTape::from_tape_with_interpolation(&tape, Encoding::Utf8)?;
+        assert_eq!(
+            interpolated_tape.warnings(),
+            [
+                "Unexpanded parameter: $amount$",
+                "Unexpanded parameter: [[bonus]",
+                "Unexpanded parameter: $bonus$",
+                "Unexpanded parameter: [[!bonus]",
+                "Unexpanded parameter: $title$",
+            ]
+        );
+        let mut output = Vec::new();
+        interpolated_tape.to_script_writer(&mut output)?;
+        let expected = "effect={\n\tadd_gold=$amount$\n\t[[bonus]\n\t\tadd_prestige=$bonus$\n\t]\n\t[[!bonus]\n\t\tadd_piety=1\n\t]\n\tname=\"$title$_desc\"\n}\n";
+        assert_eq!(String::from_utf8(output)?, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_escapes_substituted_text() -> Result<(), Box<dyn std::error::Error>> {
+        let data = br#"name = "$title$ the great" quote = "\"x\"" path = "a\\b""#;
+        let tape = TextTape::from_slice(data)?;
+        let params = HashMap::from([(String::from("title"), String::from("say \"hi\""))]);
+        let options = InterpolationOptions::new(Encoding::Utf8).with_params(params);
+        let interpolated_tape = InterpolatedTape::from_tape_with_options(&tape, options)?;
+
+        // Only the substituted text is escaped, the rest is written as it was
+        let mut output = Vec::new();
+        interpolated_tape.to_script_writer(&mut output)?;
+        let expected = "name=\"say \\\"hi\\\" the great\"\nquote=\"\\\"x\\\"\"\npath=\"a\\\\b\"\n";
+        assert_eq!(String::from_utf8(output)?, expected);
+
+        Ok(())
+    }
+
     #[test]
     fn test_direct_variable_reference() -> Result<(), Box<dyn std::error::Error>> {
         let data = br#"
@@ -937,6 +2492,156 @@ result = @derived_value
         Ok(())
     }
 
+    #[test]
+    fn test_redefined_variable_uses_preceding_declaration() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let data = br#"
+@x = 1
+a = @x
+@x = @[x + 1]
+b = @x
+"#;
+
+        let tape = TextTape::from_slice(data)?;
+        let interpolated_tape =
+            InterpolatedTape::from_tape_with_interpolation(&tape, Encoding::Utf8)?;
+        assert_eq!(interpolated_tape.to_json(), r#"{"a":1,"b":2}"#);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cyclic_variable_references() -> Result<(), Box<dyn std::error::Error>> {
+        let data = br#"
+@a = @b
+@b = @[c * 2]
+@c = @a
+x = @a
+"#;
+
+        let tape = TextTape::from_slice(data)?;
+        let error = InterpolatedTape::from_tape_with_interpolation(&tape, Encoding::Utf8)
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(error, "Cyclic variable references: @a -> @b -> @c -> @a");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_variables() -> Result<(), Box<dyn std::error::Error>> {
+        let data = br#"
+@icon = "gfx/interface/icons/gold.dds"
+@folder = gfx/units
+@tag = FRA
+@name = "Grand Army"
+@size = 3
+unit = {
+  icon = @icon
+  sprite = @[folder + "/" + tag + ".dds"]
+  label = "@name"
+  title = @name
+  size_label = @["x" + size]
+}
+@tag = { leader = yes }
+"#;
+
+        let tape = TextTape::from_slice(data)?;
+        let interpolated_tape =
+            InterpolatedTape::from_tape_with_interpolation(&tape, Encoding::Utf8)?;
+
+        let json_output = interpolated_tape.to_json();
+        let expected_json = r#"{"unit":{"icon":"gfx/interface/icons/gold.dds","sprite":"gfx/units/FRA.dds","label":"Grand Army","title":"Grand Army","size_label":"x3"},"FRA":{"leader":true}}"#;
+        assert_eq!(json_output, expected_json);
+
+        // Text that contains whitespace is quoted so it remains one scalar
+        let mut output = Vec::new();
+        interpolated_tape.to_script_writer(&mut output)?;
+        let expected = "unit={\n\ticon=gfx/interface/icons/gold.dds\n\tsprite=gfx/units/FRA.dds\n\tlabel=\"Grand Army\"\n\ttitle=\"Grand Army\"\n\tsize_label=x3\n}\nFRA={\n\tleader=yes\n}\n";
+        assert_eq!(String::from_utf8(output)?, expected);
+
+        let error = |expr: &str| {
+            let data = format!("@name = \"text\"\nx = @[{}]", expr);
+            let tape = TextTape::from_slice(data.as_bytes()).unwrap();
+            InterpolatedTape::from_tape_with_interpolation(&tape, Encoding::Utf8)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(error("name * 2"), "Expected a number, found text \"text\"");
+        assert_eq!(
+            error("max(name, 1)"),
+            "Expected a number, found text \"text\""
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_variable_declarations_with_collected_errors() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let data = br#"
+@a = 2
+@b = @[a / 0]
+x = @b
+y = @[a + missing]
+"#;
+
+        let tape = TextTape::from_slice(data)?;
+        let options = InterpolationOptions::new(Encoding::Utf8)
+            .with_source("test.txt", data)
+            .with_collected_errors();
+        let interpolated_tape = InterpolatedTape::from_tape_with_options(&tape, options)?;
+
+        let errors = interpolated_tape.errors();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("Division by zero\n --> test.txt:3:6"));
+        assert!(errors[1].starts_with("Unknown operand: missing\n --> test.txt:5:5"));
+
+        let declarations = interpolated_tape.variable_declarations();
+        let summary: Vec<_> = declarations
+            .iter()
+            .map(|x| {
+                (
+                    x.name.as_str(),
+                    x.value.clone(),
+                    x.definition.as_str(),
+                    x.uses,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("a", Some(VariableValue::Number(2.0)), "2", 2),
+                ("b", None, "@[a / 0]", 1),
+            ]
+        );
+        assert_eq!(
+            declarations[1].location,
+            Some(Location { line: 3, column: 1 })
+        );
+
+        let undefined = interpolated_tape.undefined_variables();
+        assert_eq!(
+            undefined,
+            &[VariableReference {
+                name: String::from("missing"),
+                location: Some(Location { line: 5, column: 5 }),
+                file: None,
+            }]
+        );
+
+        // Tokens with errors are left as written
+        assert_eq!(
+            interpolated_tape.to_json(),
+            r#"{"x":"@b","y":"@[a + missing]"}"#
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_unresolved_variable_reference_error() -> Result<(), Box<dyn std::error::Error>> {
         // Test that unresolved references produce clear error messages
@@ -956,6 +2661,83 @@ test = @missing_ref
         Ok(())
     }
 
+    #[test]
+    fn test_error_locations() -> Result<(), Box<dyn std::error::Error>> {
+        let data = b"@a = @[missing * 2]\nx = {\n\ty = @[1 / 0]\n}\n@c = @d\n";
+        let tape = TextTape::from_slice(data)?;
+        let options = InterpolationOptions::new(Encoding::Utf8).with_source("units.txt", data);
+        let error = InterpolatedTape::from_tape_with_options(&tape, options)
+            .err()
+            .unwrap()
+            .to_string();
+
+        let expected = "\
+Unknown operand: missing
+ --> units.txt:1:6
+  |
+1 | @a = @[missing * 2]
+  |      ^^^^^^^^^^^^^^
+
+Division by zero
+ --> units.txt:3:6
+  |
+3 | \ty = @[1 / 0]
+  | \t    ^^^^^^^^
+
+Unresolved variable references: @c -> @d
+ --> units.txt:5:6
+  |
+5 | @c = @d
+  |      ^^";
+        assert_eq!(error, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_locations_with_splices() -> Result<(), Box<dyn std::error::Error>> {
+        // `x = { inline_script = a }` with `a.txt` holding two lines
+        let original = b"x = {\n\tinline_script = a\n\ty = @[2 / 0]\n}\n";
+        let script: &[u8] = b"z = 1\nw = @[1 / 0]";
+        let data = b"x = {\n\tz = 1\nw = @[1 / 0]\n\n\ty = @[2 / 0]\n}\n";
+        let splices = [
+            Splice {
+                start: 0,
+                end: 7,
+                file: None,
+                offset: 0,
+            },
+            Splice {
+                start: 7,
+                end: 25,
+                file: Some(("a.txt", script)),
+                offset: 0,
+            },
+            Splice {
+                start: 26,
+                end: data.len(),
+                file: None,
+                offset: 24,
+            },
+        ];
+
+        let tape = TextTape::from_slice(data)?;
+        let options = InterpolationOptions::new(Encoding::Utf8)
+            .with_source("units.txt", data)
+            .with_splices(original, &splices)
+            .with_collected_errors();
+        let interpolated_tape = InterpolatedTape::from_tape_with_options(&tape, options)?;
+
+        let errors = interpolated_tape.errors();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("Division by zero\n --> a.txt:2:5\n  |\n2 | w = @[1 / 0]"));
+        assert!(
+            errors[1].starts_with("Division by zero\n --> units.txt:3:6\n  |\n3 | \ty = @[2 / 0]")
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_interpolation_with_duplicate_keys_preserve() -> Result<(), Box<dyn std::error::Error>> {
         let data = br#"
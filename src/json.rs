@@ -1,7 +1,10 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use argh::FromArgs;
 use ck3save::Ck3Melt;
-use eu4save::{file::Eu4ParsedText, Eu4File};
+use eu4save::{
+    file::{Eu4FileEntryName, Eu4ParsedText, Eu4SliceFileKind},
+    Eu4File,
+};
 use eu5save::Eu5Melt;
 use hoi4save::{file::Hoi4ParsedText, Hoi4File};
 use imperator_save::ImperatorMelt;
@@ -10,46 +13,120 @@ use jomini::{
     json::{DuplicateKeyMode, JsonOptions},
     TextTape,
 };
+use serde::Serialize;
 use std::{
-    io::{BufWriter, Cursor},
-    path::PathBuf,
+    collections::HashMap,
+    io::{self, BufWriter, Cursor, Read, Write},
+    path::{Path, PathBuf},
 };
 use vic3save::Vic3Melt;
 
 use crate::{
-    interpolation::InterpolatedTape,
+    duplicate_keys::DuplicateKeyRules,
+    flatten::write_flattened,
+    inline_scripts::expand_inline_scripts,
+    interpolation::{InterpolatedTape, InterpolationOptions, VariableValue},
+    localization::{is_localization, parse_localization},
+    operators::{parse_operators, preserve_operators, OperatorMode},
+    output::{parse_output_format, write_value, OutputFormat},
     tokens::{
         ck3_tokens_resolver, eu4_tokens_resolver, eu5_tokens_resolver, hoi4_tokens_resolver,
         imperator_tokens_resolver, vic3_tokens_resolver,
     },
+    value::Value,
 };
 
 /// convert save and game files to json
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "json")]
 pub(crate) struct JsonCommand {
-    /// specify the format of the input: utf-8 | windows-1252
-    #[argh(option, short = 'f', default = "String::from(\"windows-1252\")")]
+    /// specify the format of the input: auto | utf-8 | windows-1252
+    #[argh(option, short = 'f', default = "String::from(\"auto\")")]
     format: String,
 
     /// specify how to handle duplicate keys: preserve | group | key-value-pairs
     #[argh(option, short = 'k', default = "String::from(\"preserve\")")]
     duplicate_keys: String,
 
+    /// group duplicate keys at paths matching the pattern (eg:
+    /// `**.history.*`), takes precedence over --duplicate-keys
+    #[argh(option)]
+    group: Vec<String>,
+
+    /// write objects containing keys at paths matching the pattern as
+    /// key-value pairs, takes precedence over --group
+    #[argh(option)]
+    kv_pairs: Vec<String>,
+
+    /// file of per-path duplicate key rules, one `<mode> <pattern>` per line
+    #[argh(option)]
+    duplicate_key_rules: Option<PathBuf>,
+
     /// pretty-print json
     #[argh(switch)]
     pretty: bool,
 
+    /// sort object keys, normalize numbers, and pretty-print so that
+    /// equivalent files produce identical output
+    #[argh(switch)]
+    canonical: bool,
+
+    /// specify the output format: json | yaml | toml | msgpack | cbor
+    #[argh(option, default = "String::from(\"json\")")]
+    output_format: String,
+
     /// perform variable interpolation and convert exists operators to equals (requires --format)
     #[argh(switch)]
     interpolation: bool,
 
-    /// file to melt. Omission reads from stdin
+    /// file or directory of scripted variables to load before interpolating,
+    /// may be repeated with later files overriding earlier ones
+    #[argh(option)]
+    vars_from: Vec<PathBuf>,
+
+    /// parameter of scripted effects and triggers as `NAME=VALUE` that
+    /// replaces `$NAME$` and selects `[[NAME]` blocks when interpolating,
+    /// may be repeated
+    #[argh(option, long = "param")]
+    params: Vec<String>,
+
+    /// root directory of the mod that `inline_script` entries are read from
+    /// (`common/inline_scripts`) and expanded when interpolating
+    #[argh(option)]
+    mod_root: Option<PathBuf>,
+
+    /// include the save header and metadata alongside the gamestate (or the
+    /// meta, gamestate, and ai entries of an EU4 zip)
+    #[argh(switch)]
+    with_meta: bool,
+
+    /// remove color and text formatting codes from localization (.yml) files
+    #[argh(switch)]
+    strip_formatting: bool,
+
+    /// specify how comparison operators are written: name (eg:
+    /// `{"GREATER_THAN":5}`) | preserve (eg: `{"op":">","value":5}`)
+    #[argh(option, default = "String::from(\"name\")")]
+    operators: String,
+
+    /// print one `path = value` line per leaf value instead of json
+    #[argh(switch)]
+    flatten: bool,
+
+    /// when converting a directory, write one json line per file instead of
+    /// a single object keyed by file path
+    #[argh(switch)]
+    ndjson: bool,
+
+    /// file or directory of script files to convert
     #[argh(positional)]
     file: PathBuf,
 }
 
-fn parse_duplicate_keys(s: &str) -> anyhow::Result<DuplicateKeyMode> {
+/// Extensions of script files converted when given a directory
+const SCRIPT_EXTENSIONS: [&str; 6] = ["txt", "gui", "gfx", "asset", "sfx", "yml"];
+
+pub(crate) fn parse_duplicate_keys(s: &str) -> anyhow::Result<DuplicateKeyMode> {
     match s.to_lowercase().as_str() {
         "preserve" => Ok(DuplicateKeyMode::Preserve),
         "group" => Ok(DuplicateKeyMode::Group),
@@ -64,14 +141,61 @@ pub enum Encoding {
     Windows1252,
 }
 
-fn parse_encoding(s: &str) -> anyhow::Result<Encoding> {
+pub(crate) fn parse_encoding(s: &str) -> anyhow::Result<Encoding> {
     match s.to_lowercase().as_str() {
-        "utf-8" => Ok(Encoding::Utf8),
+        "utf-8" | "utf8" => Ok(Encoding::Utf8),
         "windows-1252" => Ok(Encoding::Windows1252),
         _ => Err(anyhow!("Unrecognized encoding option")),
     }
 }
 
+/// Parse the encoding of an input, where `None` means it should be detected
+pub(crate) fn parse_input_encoding(s: &str) -> anyhow::Result<Option<Encoding>> {
+    if s.eq_ignore_ascii_case("auto") {
+        Ok(None)
+    } else {
+        parse_encoding(s).map(Some)
+    }
+}
+
+pub(crate) const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Detect the encoding of the data: utf-8 when it starts with a BOM or is
+/// valid utf-8, otherwise windows-1252. Returns whether the data appears to
+/// mix both encodings too.
+pub(crate) fn detect_encoding(data: &[u8]) -> (Encoding, bool) {
+    if data.starts_with(UTF8_BOM) {
+        return (Encoding::Utf8, false);
+    }
+
+    let mut rest = data;
+    let mut multibyte = false;
+    let mut invalid = false;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                multibyte |= !valid.is_ascii();
+                break;
+            }
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                multibyte |= !valid.is_ascii();
+                invalid = true;
+                match e.error_len() {
+                    Some(len) => rest = &after[len..],
+                    None => break,
+                }
+            }
+        }
+    }
+
+    if invalid {
+        (Encoding::Windows1252, multibyte)
+    } else {
+        (Encoding::Utf8, false)
+    }
+}
+
 impl JsonCommand {
     pub(crate) fn exec(&self) -> anyhow::Result<i32> {
         // Validate that interpolation flag is only used with generic files (not game files)
@@ -84,72 +208,509 @@ impl JsonCommand {
                 return Err(anyhow!("--interpolation flag can only be used with generic files (not game-specific file extensions), requires --format"));
             }
         }
-        let extension = self.file.extension().and_then(|x| x.to_str());
+        if !self.vars_from.is_empty() && !self.interpolation {
+            return Err(anyhow!("--vars-from requires --interpolation"));
+        }
+        if !self.params.is_empty() && !self.interpolation {
+            return Err(anyhow!("--param requires --interpolation"));
+        }
+        if self.mod_root.is_some() && !self.interpolation {
+            return Err(anyhow!("--mod-root requires --interpolation"));
+        }
+
+        let output = parse_output_format(&self.output_format)?;
+        let operators = parse_operators(&self.operators)?;
+        if self.flatten && output != OutputFormat::Json {
+            return Err(anyhow!("--flatten can't be combined with --output-format"));
+        }
+
+        if self.file.is_dir() {
+            return self.exec_directory(output);
+        }
+
+        let variables = load_variables(&self.vars_from, parse_input_encoding(&self.format)?)?;
+        let params = parse_params(&self.params)?;
         let data = std::fs::read(&self.file)?;
+        let source = JsonSource {
+            file: &self.file,
+            encoding: parse_input_encoding(&self.format)?,
+            interpolation: self.interpolation,
+            with_meta: self.with_meta,
+            strip_formatting: self.strip_formatting,
+            preserve_operators: operators == OperatorMode::Preserve,
+            variables: Some(&variables),
+            params: params.as_ref(),
+            mod_root: self.mod_root.as_deref(),
+        };
+
+        let stdout = std::io::stdout();
+        let mut writer = BufWriter::new(stdout.lock());
+        let rules = self.duplicate_key_rules()?;
+
+        // Sections of --with-meta and localization files are not written by
+        // the json serializer, so pretty printing them requires a round trip
+        let round_trip = self.canonical
+            || self.flatten
+            || !rules.is_empty()
+            || operators == OperatorMode::Preserve
+            || (self.pretty && (self.with_meta || is_localization(&self.file)));
+        let result = if output == OutputFormat::Json && !round_trip {
+            let options = self.json_options()?.with_prettyprint(self.pretty);
+            source.write_json(&data, options, writer)
+        } else {
+            let value = self.to_value(&source, &data, &rules)?;
+            self.write_document(&value, output, &mut writer)
+        };
+
+        ignore_broken_pipe(result).map(|_| 0)
+    }
+
+    /// Write a converted document in the requested output format
+    fn write_document<W: Write>(
+        &self,
+        value: &Value,
+        output: OutputFormat,
+        mut writer: W,
+    ) -> anyhow::Result<()> {
+        if self.flatten {
+            return write_flattened(value, writer);
+        }
+
+        write_value(value, output, self.pretty || self.canonical, &mut writer)?;
+
+        // Canonical json always ends with a newline, as is expected of text files
+        if self.canonical && output == OutputFormat::Json {
+            writer.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+
+    fn json_options(&self) -> anyhow::Result<JsonOptions> {
         let keys = parse_duplicate_keys(&self.duplicate_keys)?;
-        let options = JsonOptions::new()
-            .with_prettyprint(self.pretty)
-            .with_duplicate_keys(keys);
+        Ok(JsonOptions::new().with_duplicate_keys(keys))
+    }
+
+    fn duplicate_key_rules(&self) -> anyhow::Result<DuplicateKeyRules> {
+        let mut rules = DuplicateKeyRules::new(parse_duplicate_keys(&self.duplicate_keys)?);
+        for pattern in &self.kv_pairs {
+            rules.push(pattern, DuplicateKeyMode::KeyValuePairs);
+        }
+
+        for pattern in &self.group {
+            rules.push(pattern, DuplicateKeyMode::Group);
+        }
+
+        if let Some(file) = &self.duplicate_key_rules {
+            rules.push_file(file)?;
+        }
+
+        Ok(rules)
+    }
+
+    /// Convert the file into a document with the duplicate key rules and
+    /// canonicalization applied
+    fn to_value(
+        &self,
+        source: &JsonSource,
+        data: &[u8],
+        rules: &DuplicateKeyRules,
+    ) -> anyhow::Result<Value> {
+        let mut value = if rules.is_empty() {
+            source.to_value(data, self.json_options()?)?
+        } else {
+            let options = JsonOptions::new().with_duplicate_keys(DuplicateKeyMode::Preserve);
+            rules.apply(source.to_value(data, options)?)
+        };
+
+        if parse_operators(&self.operators)? == OperatorMode::Preserve {
+            preserve_operators(&mut value);
+        }
+
+        if self.canonical {
+            value.canonicalize();
+        }
+
+        Ok(value)
+    }
+
+    /// Convert every script file in the directory, reporting files that fail
+    /// to parse without aborting the rest
+    fn exec_directory(&self, output: OutputFormat) -> anyhow::Result<i32> {
+        if self.ndjson && output != OutputFormat::Json {
+            return Err(anyhow!("--ndjson can only be used with json output"));
+        }
+
+        if self.ndjson && self.flatten {
+            return Err(anyhow!("--ndjson can't be combined with --flatten"));
+        }
+
+        let encoding = parse_input_encoding(&self.format)?;
+        let mut files = Vec::new();
+        script_files(&self.file, &mut files)
+            .with_context(|| format!("Failed to read directory: {}", self.file.display()))?;
+        files.sort();
+
+        let rules = self.duplicate_key_rules()?;
+        let operators = parse_operators(&self.operators)?;
+        let variables = load_variables(&self.vars_from, encoding)?;
+        let params = parse_params(&self.params)?;
+        let stdout = std::io::stdout();
+        let mut writer = BufWriter::new(stdout.lock());
+        let mut entries = Vec::new();
+        let mut failures = 0;
+        for path in files {
+            let relative = path.strip_prefix(&self.file).unwrap_or(&path);
+            let relative = relative.to_string_lossy().replace('\\', "/");
+            let source = JsonSource {
+                file: &path,
+                encoding,
+                interpolation: self.interpolation,
+                with_meta: self.with_meta,
+                strip_formatting: self.strip_formatting,
+                preserve_operators: operators == OperatorMode::Preserve,
+                variables: Some(&variables),
+                params: params.as_ref(),
+                mod_root: self.mod_root.as_deref(),
+            };
+
+            let value = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| self.to_value(&source, &data, &rules));
+
+            match value {
+                Ok(value) if self.ndjson => {
+                    let line = Value::Object(vec![
+                        (String::from("path"), Value::String(relative)),
+                        (String::from("data"), value),
+                    ]);
+                    let result = serde_json::to_writer(&mut writer, &line)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|_| Ok(writer.write_all(b"\n")?));
+                    ignore_broken_pipe(result)?;
+                }
+                Ok(value) => entries.push((relative, value)),
+                Err(e) => {
+                    failures += 1;
+                    eprintln!("{}: {:#}", relative, e);
+                }
+            }
+        }
+
+        if !self.ndjson {
+            let result = self.write_document(&Value::Object(entries), output, &mut writer);
+            ignore_broken_pipe(result)?;
+        }
+
+        ignore_broken_pipe(writer.flush().map_err(anyhow::Error::from))?;
+        Ok(if failures > 0 { 1 } else { 0 })
+    }
+}
+
+/// Ignore io errors when writing to stdout so that one can pipe the output
+/// to subsequent commands without fail
+fn ignore_broken_pipe(result: anyhow::Result<()>) -> anyhow::Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => match e.chain().find_map(|ie| ie.downcast_ref::<io::Error>()) {
+            Some(io_err) if matches!(io_err.kind(), io::ErrorKind::BrokenPipe) => Ok(()),
+            _ => Err(e),
+        },
+    }
+}
+
+/// Recursively collect the script files within a directory
+fn script_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        // Symlinked directories aren't followed as they may loop back onto
+        // one of their parents
+        if entry.file_type()?.is_dir() {
+            script_files(&path, files)?;
+        } else if path.is_file()
+            && path
+                .extension()
+                .and_then(|x| x.to_str())
+                .is_some_and(|x| SCRIPT_EXTENSIONS.contains(&x.to_lowercase().as_str()))
+        {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn write_eu4_json<W: Write>(data: &[u8], options: JsonOptions, writer: W) -> anyhow::Result<()> {
+    let file = Eu4File::from_slice(data)?;
+    let mut out = Cursor::new(Vec::new());
+    let text = if file.encoding().is_binary() || file.encoding().is_zip() {
+        let options = eu4save::MeltOptions::new()
+            .on_failed_resolve(jomini::binary::FailedResolveStrategy::Ignore)
+            .verbatim(true);
+        file.melt(options, eu4_tokens_resolver(), &mut out)?;
+        Eu4ParsedText::from_slice(out.get_ref().as_slice())?
+    } else {
+        Eu4ParsedText::from_slice(data)?
+    };
+
+    text.reader()
+        .json()
+        .with_options(options)
+        .to_writer(writer)?;
+    Ok(())
+}
+
+/// Melt a save stored in the envelope shared by EU5, CK3, Imperator, and
+/// Victoria 3 into plaintext (header included)
+fn melt_envelope(data: &[u8], extension: Option<&str>) -> anyhow::Result<Vec<u8>> {
+    let verbatim = true;
+    let strategy = jomini::binary::FailedResolveStrategy::Ignore;
+    let file = jomini::envelope::JominiFile::from_slice(data)?;
+    let mut out = Cursor::new(Vec::new());
+    match extension {
+        Some("eu5") => {
+            let options = eu5save::MeltOptions::new()
+                .on_failed_resolve(strategy)
+                .verbatim(verbatim);
+            let resolver = eu5save::SaveResolver::from_file(&file, eu5_tokens_resolver())?;
+            Eu5Melt::melt(&mut (&file), options, &resolver, &mut out)?;
+        }
+        Some("ck3") => {
+            let options = ck3save::MeltOptions::new()
+                .on_failed_resolve(strategy)
+                .verbatim(verbatim);
+            Ck3Melt::melt(&mut (&file), options, ck3_tokens_resolver(), &mut out)?;
+        }
+        Some("rome") => {
+            let options = imperator_save::MeltOptions::new()
+                .on_failed_resolve(strategy)
+                .verbatim(verbatim);
+            ImperatorMelt::melt(&mut (&file), options, imperator_tokens_resolver(), &mut out)?;
+        }
+        Some("v3") => {
+            let options = vic3save::MeltOptions::new()
+                .on_failed_resolve(strategy)
+                .verbatim(verbatim);
+            Vic3Melt::melt(&mut (&file), options, vic3_tokens_resolver(), &mut out)?;
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(out.into_inner())
+}
+
+/// The first line of an EU5, CK3, Imperator, or Victoria 3 save
+/// (eg: `SAV0102a9a1b0ba00000ea4`)
+#[derive(Debug, PartialEq, Serialize)]
+struct SaveHeader {
+    version: u8,
+    kind: &'static str,
+    binary: bool,
+    compressed: bool,
+    meta_length: u64,
+}
+
+impl SaveHeader {
+    /// Parse the header line, returning it with the length of the line
+    fn parse(data: &[u8]) -> anyhow::Result<(SaveHeader, usize)> {
+        let line_len = data
+            .iter()
+            .position(|&x| x == b'\n')
+            .ok_or_else(|| anyhow!("Save header is missing a newline"))?;
+        let line = std::str::from_utf8(&data[..line_len])
+            .ok()
+            .map(|x| x.trim_end_matches('\r'))
+            .filter(|x| x.len() >= 23 && x.starts_with("SAV") && x.is_ascii())
+            .ok_or_else(|| anyhow!("Unrecognized save header"))?;
+
+        let hex = |x: &str| u64::from_str_radix(x, 16).context("Unrecognized save header");
+        let version = hex(&line[3..5])? as u8;
+        let kind = match hex(&line[5..7])? {
+            0 => "text",
+            1 => "binary",
+            2 => "unified_text",
+            3 => "unified_binary",
+            4 => "split_text",
+            5 => "split_binary",
+            x => return Err(anyhow!("Unrecognized save header kind: {}", x)),
+        };
+
+        let header = SaveHeader {
+            version,
+            kind,
+            binary: kind.ends_with("binary"),
+            compressed: kind != "text" && kind != "binary",
+            meta_length: hex(&line[15..])?,
+        };
+
+        Ok((header, line_len + 1))
+    }
+}
+
+/// The encoding of a generic file, detected when not given
+pub(crate) fn resolve_encoding(encoding: Option<Encoding>, file: &Path, data: &[u8]) -> Encoding {
+    match encoding {
+        Some(encoding) => encoding,
+        None => {
+            let (encoding, mixed) = detect_encoding(data);
+            if mixed {
+                eprintln!(
+                    "warning: {} mixes utf-8 and windows-1252 encoded text, decoding as windows-1252",
+                    file.display()
+                );
+            }
+            encoding
+        }
+    }
+}
+
+/// Parse `NAME=VALUE` parameters, returning nothing when none are given so
+/// that parameters are left as written
+pub(crate) fn parse_params(params: &[String]) -> anyhow::Result<Option<HashMap<String, String>>> {
+    if params.is_empty() {
+        return Ok(None);
+    }
+
+    params
+        .iter()
+        .map(|param| match param.split_once('=') {
+            Some((name, value)) if !name.is_empty() => Ok((name.to_owned(), value.to_owned())),
+            _ => Err(anyhow!("Expected a parameter as NAME=VALUE: {}", param)),
+        })
+        .collect::<anyhow::Result<_>>()
+        .map(Some)
+}
+
+/// Collect the variables declared in scripted variable files. Directories
+/// are read in the game's load order (sorted by path) and later files
+/// override earlier ones
+pub(crate) fn load_variables(
+    paths: &[PathBuf],
+    encoding: Option<Encoding>,
+) -> anyhow::Result<HashMap<String, VariableValue>> {
+    let mut variables: HashMap<String, VariableValue> = HashMap::new();
+    let mut origins: HashMap<String, PathBuf> = HashMap::new();
+    for path in paths {
+        let mut files = Vec::new();
+        if path.is_dir() {
+            script_files(path, &mut files)
+                .with_context(|| format!("Failed to read directory: {}", path.display()))?;
+            files.retain(|x| x.extension().is_some_and(|x| x.eq_ignore_ascii_case("txt")));
+            files.sort();
+        } else {
+            files.push(path.clone());
+        }
+
+        for file in files {
+            let data = std::fs::read(&file)
+                .with_context(|| format!("Failed to read variables: {}", file.display()))?;
+            let encoding = resolve_encoding(encoding, &file, &data);
+            let data = data.strip_prefix(UTF8_BOM).unwrap_or(&data);
+            let tape = TextTape::from_slice(data)
+                .with_context(|| format!("Failed to parse variables: {}", file.display()))?;
+
+            // Variables may be defined in terms of those from earlier files
+            let name = file.display().to_string();
+            let options = InterpolationOptions::new(encoding)
+                .with_variables(variables.clone())
+                .with_source(&name, data);
+            let tape = InterpolatedTape::from_tape_with_options(&tape, options)
+                .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+
+            for (name, value) in tape.declared_variables() {
+                if let Some(previous) = origins.insert(name.to_owned(), file.clone()) {
+                    eprintln!(
+                        "warning: @{} in {} redefines the variable from {}",
+                        name,
+                        file.display(),
+                        previous.display()
+                    );
+                }
+                variables.insert(name.to_owned(), value.clone());
+            }
+        }
+    }
+
+    Ok(variables)
+}
+
+/// A game or save file that can be converted to json
+pub(crate) struct JsonSource<'a> {
+    /// path of the file, its extension determines how the file is parsed
+    pub file: &'a Path,
+
+    /// encoding of generic (non-save) files, detected when not provided
+    pub encoding: Option<Encoding>,
+
+    /// perform variable interpolation on generic files
+    pub interpolation: bool,
+
+    /// wrap save output with the header and metadata sections
+    pub with_meta: bool,
+
+    /// remove color and text formatting codes from localization
+    pub strip_formatting: bool,
 
+    /// keep `==` and `?=` distinct from assignments when interpolating
+    pub preserve_operators: bool,
+
+    /// variables defined outside of the file, available when interpolating
+    pub variables: Option<&'a HashMap<String, VariableValue>>,
+
+    /// parameters to expand when interpolating
+    pub params: Option<&'a HashMap<String, String>>,
+
+    /// root of the mod that inline scripts are expanded from when interpolating
+    pub mod_root: Option<&'a Path>,
+}
+
+impl JsonSource<'_> {
+    /// Parse the json output into a document that can be further processed
+    pub fn to_value(&self, data: &[u8], options: JsonOptions) -> anyhow::Result<Value> {
+        let mut out = Vec::new();
+        self.write_json(data, options.with_prettyprint(false), &mut out)?;
+        Value::from_slice(&out).context("Failed to read back json output")
+    }
+
+    pub fn write_json<W: Write>(
+        &self,
+        data: &[u8],
+        options: JsonOptions,
+        writer: W,
+    ) -> anyhow::Result<()> {
+        let extension = self.file.extension().and_then(|x| x.to_str());
         let verbatim = true;
         let strategy = jomini::binary::FailedResolveStrategy::Ignore;
-        let stdout = std::io::stdout();
-        let writer = BufWriter::new(stdout.lock());
 
-        let _ = match extension {
-            Some("eu4") => {
-                let file = Eu4File::from_slice(&data)?;
-                let mut out = Cursor::new(Vec::new());
-                let text = if file.encoding().is_binary() || file.encoding().is_zip() {
-                    let options = eu4save::MeltOptions::new()
-                        .on_failed_resolve(strategy)
-                        .verbatim(verbatim);
-                    file.melt(options, eu4_tokens_resolver(), &mut out)?;
-                    Eu4ParsedText::from_slice(out.get_ref().as_slice())?
-                } else {
-                    Eu4ParsedText::from_slice(&data)?
+        match extension {
+            Some("eu4") if self.with_meta => {
+                let file = Eu4File::from_slice(data)?;
+                let Eu4SliceFileKind::Zip(zip) = file.kind() else {
+                    return Err(anyhow!("--with-meta requires an EU4 save stored as a zip"));
                 };
 
-                text.reader().json().with_options(options).to_writer(writer)
+                let mut writer = writer;
+                let entries = vec![
+                    ("meta", Eu4FileEntryName::Meta),
+                    ("gamestate", Eu4FileEntryName::Gamestate),
+                    ("ai", Eu4FileEntryName::Ai),
+                ];
+
+                for (i, (key, name)) in entries.into_iter().enumerate() {
+                    let mut entry = Vec::new();
+                    zip.get(name)?.read_to_end(&mut entry)?;
+                    let separator = if i == 0 { "{" } else { "," };
+                    write!(writer, "{}\"{}\":", separator, key)?;
+                    write_eu4_json(&entry, options, &mut writer)?;
+                }
+                writer.write_all(b"}")?;
             }
+            Some("eu4") => write_eu4_json(data, options, writer)?,
             Some("eu5" | "ck3" | "rome" | "v3") => {
-                let file = jomini::envelope::JominiFile::from_slice(&data)?;
-                let mut out = Cursor::new(Vec::new());
-                match extension {
-                    Some("eu5") => {
-                        let options = eu5save::MeltOptions::new()
-                            .on_failed_resolve(strategy)
-                            .verbatim(verbatim);
-                        let resolver = eu5save::SaveResolver::from_file(&file, eu5_tokens_resolver())?;
-                        Eu5Melt::melt(&mut (&file), options, &resolver, &mut out)?;
-                    }
-                    Some("ck3") => {
-                        let options = ck3save::MeltOptions::new()
-                            .on_failed_resolve(strategy)
-                            .verbatim(verbatim);
-                        Ck3Melt::melt(&mut (&file), options, ck3_tokens_resolver(), &mut out)?;
-                    }
-                    Some("rome") => {
-                        let options = imperator_save::MeltOptions::new()
-                            .on_failed_resolve(strategy)
-                            .verbatim(verbatim);
-                        ImperatorMelt::melt(
-                            &mut (&file),
-                            options,
-                            imperator_tokens_resolver(),
-                            &mut out,
-                        )?;
-                    }
-                    Some("v3") => {
-                        let options = vic3save::MeltOptions::new()
-                            .on_failed_resolve(strategy)
-                            .verbatim(verbatim);
-                        Vic3Melt::melt(&mut (&file), options, vic3_tokens_resolver(), &mut out)?;
-                    }
-                    _ => unreachable!(),
-                }
-
-                let file = jomini::envelope::JominiFile::from_slice(out.get_ref())?;
+                let melted = melt_envelope(data, extension)?;
+                let file = jomini::envelope::JominiFile::from_slice(melted.as_slice())?;
                 let JominiFileKind::Uncompressed(SaveDataKind::Text(txt)) = file.kind() else {
                     return Err(anyhow!("Unexpected file kind after melting"));
                 };
@@ -157,13 +718,49 @@ impl JsonCommand {
                 let all = txt.body().get_ref().get_ref().as_slice();
                 let body = &all[txt.body().content_offset() as usize..];
                 let tape = TextTape::from_slice(body)?;
-                tape.utf8_reader()
-                    .json()
-                    .with_options(options)
-                    .to_writer(writer)
+
+                if self.with_meta {
+                    // The header of the original file describes how the save
+                    // was written, while the metadata is taken from the
+                    // melted output so that it is always plaintext
+                    let (header, _) = SaveHeader::parse(data)?;
+                    let (melted_header, header_len) = SaveHeader::parse(&melted)?;
+                    let content = &melted[header_len..];
+                    let meta_len = (melted_header.meta_length as usize).min(content.len());
+                    let meta = TextTape::from_slice(&content[..meta_len])?;
+
+                    let mut writer = writer;
+                    writer.write_all(b"{\"header\":")?;
+                    serde_json::to_writer(&mut writer, &header)?;
+                    writer.write_all(b",\"meta\":")?;
+                    meta.utf8_reader()
+                        .json()
+                        .with_options(options)
+                        .to_writer(&mut writer)?;
+                    writer.write_all(b",\"gamestate\":")?;
+                    tape.utf8_reader()
+                        .json()
+                        .with_options(options)
+                        .to_writer(&mut writer)?;
+                    writer.write_all(b"}")?;
+                } else {
+                    tape.utf8_reader()
+                        .json()
+                        .with_options(options)
+                        .to_writer(writer)?;
+                }
+            }
+            _ if self.with_meta => {
+                return Err(anyhow!(
+                    "--with-meta is only supported for eu4, eu5, ck3, rome, and v3 saves"
+                ));
+            }
+            _ if is_localization(self.file) => {
+                let value = parse_localization(data, self.strip_formatting)?;
+                serde_json::to_writer(writer, &value)?;
             }
             Some("hoi4") => {
-                let file = Hoi4File::from_slice(&data)?;
+                let file = Hoi4File::from_slice(data)?;
                 let mut out = Cursor::new(Vec::new());
                 let text = if !matches!(file.encoding(), hoi4save::Encoding::Plaintext) {
                     let options = hoi4save::MeltOptions::new()
@@ -172,38 +769,122 @@ impl JsonCommand {
                     file.melt(options, hoi4_tokens_resolver(), &mut out)?;
                     Hoi4ParsedText::from_slice(out.get_ref().as_slice())?
                 } else {
-                    Hoi4ParsedText::from_slice(&data)?
+                    Hoi4ParsedText::from_slice(data)?
                 };
 
-                text.reader().json().with_options(options).to_writer(writer)
+                text.reader()
+                    .json()
+                    .with_options(options)
+                    .to_writer(writer)?;
             }
             _ => {
-                let encoding = parse_encoding(&self.format)?;
+                let encoding = resolve_encoding(self.encoding, self.file, data);
+
+                // The games require some files to start with a BOM
+                let data = data.strip_prefix(UTF8_BOM).unwrap_or(data);
 
                 if self.interpolation {
-                    let tape = jomini::TextTape::from_slice(&data)?;
+                    let expansion = expand_inline_scripts(data, self.mod_root)?;
+                    let splices = expansion.splices();
+                    let original = data;
+                    let data = expansion.data();
+                    let tape = jomini::TextTape::from_slice(data)?;
+                    let name = self.file.display().to_string();
+                    let mut interpolation_options = InterpolationOptions::new(encoding)
+                        .with_source(&name, data)
+                        .with_splices(original, &splices);
+                    if let Some(variables) = self.variables {
+                        interpolation_options =
+                            interpolation_options.with_variables(variables.clone());
+                    }
+                    if let Some(params) = self.params {
+                        interpolation_options = interpolation_options.with_params(params.clone());
+                    }
+
                     let interpolated_tape =
-                        InterpolatedTape::from_tape_with_interpolation(&tape, encoding)
+                        InterpolatedTape::from_tape_with_options(&tape, interpolation_options)
                             .map_err(|e| anyhow::Error::msg(e.to_string()))?;
-                    interpolated_tape.to_writer_with_options(writer, options, encoding)
+                    for warning in interpolated_tape.warnings() {
+                        eprintln!("warning: {}\n", warning);
+                    }
+                    interpolated_tape
+                        .with_preserved_operators(self.preserve_operators)
+                        .to_writer_with_options(writer, options, encoding)?;
                 } else {
-                    let tape = TextTape::from_slice(&data)?;
+                    let tape = TextTape::from_slice(data)?;
                     match encoding {
                         Encoding::Utf8 => tape
                             .utf8_reader()
                             .json()
                             .with_options(options)
-                            .to_writer(writer),
+                            .to_writer(writer)?,
                         Encoding::Windows1252 => tape
                             .windows1252_reader()
                             .json()
                             .with_options(options)
-                            .to_writer(writer),
+                            .to_writer(writer)?,
                     }
                 }
             }
         };
 
-        Ok(0)
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_save_header() {
+        let (header, len) = SaveHeader::parse(b"SAV0103a9a1b0ba00000ea4\nmeta_data={}").unwrap();
+        assert_eq!(len, 24);
+        assert_eq!(
+            header,
+            SaveHeader {
+                version: 1,
+                kind: "unified_binary",
+                binary: true,
+                compressed: true,
+                meta_length: 0xea4,
+            }
+        );
+
+        assert!(SaveHeader::parse(b"EU4bin\n").is_err());
+    }
+
+    #[test]
+    fn test_detect_encoding() {
+        assert!(matches!(detect_encoding(b"a=b"), (Encoding::Utf8, false)));
+        assert!(matches!(
+            detect_encoding(b"\xef\xbb\xbfname=Z\xc3\xbcrich"),
+            (Encoding::Utf8, false)
+        ));
+        assert!(matches!(
+            detect_encoding(b"name=Z\xfcrich"),
+            (Encoding::Windows1252, false)
+        ));
+        assert!(matches!(
+            detect_encoding(b"name=Z\xfcrich\nother=Z\xc3\xbcrich"),
+            (Encoding::Windows1252, true)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_script_files_symlink_loop() {
+        let dir = std::env::temp_dir().join(format!("rakaly-script-files-{}", std::process::id()));
+        let common = dir.join("common");
+        std::fs::create_dir_all(&common).unwrap();
+        std::fs::write(common.join("a.txt"), "a=b").unwrap();
+        std::os::unix::fs::symlink(&dir, common.join("loop")).unwrap();
+
+        let mut files = Vec::new();
+        let result = script_files(&dir, &mut files);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        result.unwrap();
+        assert_eq!(files, vec![common.join("a.txt")]);
     }
 }
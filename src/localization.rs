@@ -0,0 +1,155 @@
+use anyhow::{anyhow, Context};
+use std::path::Path;
+
+use crate::{json::UTF8_BOM, value::Value};
+
+/// Returns true if the file is a localization file (eg: `foo_l_english.yml`)
+pub(crate) fn is_localization(file: &Path) -> bool {
+    file.extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| x.eq_ignore_ascii_case("yml"))
+}
+
+/// Parse a localization file into `{language: {key: {version, text}}}`.
+///
+/// Localization files look like yaml, but aren't: text may contain
+/// unescaped quotes and the version number is attached to the key:
+///
+/// ```plain
+/// l_english:
+///  KEY:0 "text with $var$ and §Ycolor§!"
+/// ```
+pub(crate) fn parse_localization(data: &[u8], strip_formatting: bool) -> anyhow::Result<Value> {
+    let data = data.strip_prefix(UTF8_BOM).unwrap_or(data);
+    let data = std::str::from_utf8(data).context("Localization files must be utf-8 encoded")?;
+
+    let mut languages: Vec<(String, Value)> = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let (key, rest) = trimmed
+            .split_once(':')
+            .ok_or_else(|| anyhow!("line {}: expected a key followed by a colon", i + 1))?;
+
+        let rest = rest.trim();
+        if rest.is_empty() || rest.starts_with('#') {
+            languages.push((key.to_owned(), Value::Object(Vec::new())));
+            continue;
+        }
+
+        let (version, text) = parse_entry(rest)
+            .ok_or_else(|| anyhow!("line {}: expected a quoted value for {}", i + 1, key))?;
+
+        let text = if strip_formatting {
+            strip_formatting_codes(&text)
+        } else {
+            text
+        };
+
+        let entry = Value::Object(vec![
+            (
+                String::from("version"),
+                version.map_or(Value::Null, |x| Value::Number(x.into())),
+            ),
+            (String::from("text"), Value::String(text)),
+        ]);
+
+        match languages.last_mut() {
+            Some((_, Value::Object(entries))) => entries.push((key.to_owned(), entry)),
+            _ => return Err(anyhow!("line {}: {} is not under a language", i + 1, key)),
+        }
+    }
+
+    Ok(Value::Object(languages))
+}
+
+/// Split `0 "text"` into the optional version and the unescaped text. Text
+/// may contain unescaped quotes, so it is closed by the first unescaped quote
+/// that is only followed by whitespace or a comment
+fn parse_entry(rest: &str) -> Option<(Option<u32>, String)> {
+    let start = rest.find('"')?;
+    let mut chars = rest[start + 1..].char_indices();
+    let mut end = None;
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => {
+                let after = rest[start + 1 + i + 1..].trim_start();
+                if after.is_empty() || after.starts_with('#') {
+                    end = Some(start + 1 + i);
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let version = rest[..start].trim();
+    let version = if version.is_empty() {
+        None
+    } else {
+        Some(version.parse().ok()?)
+    };
+
+    let text = rest[start + 1..end?].replace("\\\"", "\"");
+    Some((version, text))
+}
+
+/// Remove color codes (`§Y` ... `§!`) and text formatting (`#bold` ... `#!`)
+/// while leaving variables and scope references untouched
+fn strip_formatting_codes(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '§' => {
+                chars.next();
+            }
+            '#' if chars.peek() == Some(&'!') => {
+                chars.next();
+            }
+            '#' if chars.peek().is_some_and(|x| x.is_alphabetic()) => {
+                while chars.next_if(|x| !x.is_whitespace()).is_some() {}
+                chars.next_if_eq(&' ');
+            }
+            _ => result.push(c),
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_localization() {
+        let data = "\u{feff}l_english:\n # comment\n KEY:0 \"The §Y$NAME$§! says \"hi\"\"\n OTHER: \"[Root.GetName]\" # a \"comment\"\n ESCAPED:1 \"says \\\"bye\\\"\"\n";
+        let value = parse_localization(data.as_bytes(), false).unwrap();
+        assert_eq!(
+            serde_json::to_string(&value).unwrap(),
+            r#"{"l_english":{"KEY":{"version":0,"text":"The §Y$NAME$§! says \"hi\""},"OTHER":{"version":null,"text":"[Root.GetName]"},"ESCAPED":{"version":1,"text":"says \"bye\""}}}"#
+        );
+    }
+
+    #[test]
+    fn test_parse_localization_errors() {
+        assert!(parse_localization(b"KEY:0 \"text\"", false).is_err());
+        assert!(parse_localization(b"l_english:\n KEY:0 text", false).is_err());
+        assert!(parse_localization(b"l_english:\n KEY:0 \"text", false).is_err());
+    }
+
+    #[test]
+    fn test_strip_formatting_codes() {
+        assert_eq!(
+            strip_formatting_codes("The §Y$NAME$§! is #bold very#! [Root.GetName]"),
+            "The $NAME$ is very [Root.GetName]"
+        );
+    }
+}
@@ -1,8 +1,22 @@
 mod cli;
+mod columnar;
+mod duplicate_keys;
+mod flatten;
+mod inline_scripts;
+mod interpolate;
 mod interpolation;
 mod json;
+mod localization;
 mod melt;
+mod operators;
+mod output;
+mod schema;
+mod sqlite;
+mod table;
+mod text;
 mod tokens;
+mod value;
+mod vars;
 mod watch;
 
 fn main() {
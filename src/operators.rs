@@ -0,0 +1,109 @@
+use anyhow::anyhow;
+
+use crate::value::Value;
+
+/// The names jomini writes for operators (eg: `{"GREATER_THAN": 5}`) and
+/// their symbols in game script
+pub(crate) const OPERATORS: [(&str, &str); 8] = [
+    ("LESS_THAN", "<"),
+    ("LESS_THAN_EQUAL", "<="),
+    ("GREATER_THAN", ">"),
+    ("GREATER_THAN_EQUAL", ">="),
+    ("NOT_EQUAL", "!="),
+    ("EQUAL", "="),
+    ("EXACT", "=="),
+    ("EXISTS", "?="),
+];
+
+/// How operators other than assignment are represented in json
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OperatorMode {
+    /// `{"GREATER_THAN_EQUAL": 5}`, with `==` and `?=` becoming assignments
+    /// when interpolating
+    Name,
+
+    /// `{"op": ">=", "value": 5}`, with every operator kept distinct
+    Preserve,
+}
+
+pub(crate) fn parse_operators(s: &str) -> anyhow::Result<OperatorMode> {
+    match s.to_lowercase().as_str() {
+        "name" => Ok(OperatorMode::Name),
+        "preserve" => Ok(OperatorMode::Preserve),
+        _ => Err(anyhow!("Unrecognized operators option")),
+    }
+}
+
+/// Rewrite operators from their name representation to the explicit
+/// `{"op": ">=", "value": 5}` representation
+pub(crate) fn preserve_operators(value: &mut Value) {
+    match value {
+        Value::Array(values) => values.iter_mut().for_each(preserve_operators),
+        Value::Object(entries) => {
+            let symbol = match entries.as_slice() {
+                [(name, _)] => OPERATORS
+                    .iter()
+                    .find(|(x, _)| *x == name.as_str())
+                    .map(|(_, symbol)| *symbol),
+                _ => None,
+            };
+
+            match (symbol, entries.pop()) {
+                (Some(symbol), Some((_, mut inner))) => {
+                    preserve_operators(&mut inner);
+                    *value = Value::Object(vec![
+                        (String::from("op"), Value::String(String::from(symbol))),
+                        (String::from("value"), inner),
+                    ]);
+                }
+                (_, last) => {
+                    entries.extend(last);
+                    entries.iter_mut().for_each(|(_, x)| preserve_operators(x));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Detect either representation of an operator and return its symbol and
+/// the operand
+pub(crate) fn split_operator(value: &Value) -> (&'static str, &Value) {
+    if let Value::Object(entries) = value {
+        match entries.as_slice() {
+            [(name, inner)] => {
+                if let Some((_, symbol)) = OPERATORS.iter().find(|(x, _)| *x == name.as_str()) {
+                    return (*symbol, inner);
+                }
+            }
+            [(op, Value::String(symbol)), (key, inner)] if op == "op" && key == "value" => {
+                if let Some((_, symbol)) = OPERATORS.iter().find(|(_, x)| *x == symbol.as_str()) {
+                    return (*symbol, inner);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    ("=", value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preserve_operators() {
+        let data = r#"{"a":{"GREATER_THAN_EQUAL":5},"b":{"EXISTS":{"c":{"LESS_THAN":1}}},"d":[{"EXACT":2}],"e":{"NOT_EQUAL":1,"f":2}}"#;
+        let mut value = Value::from_slice(data.as_bytes()).unwrap();
+        preserve_operators(&mut value);
+        assert_eq!(
+            serde_json::to_string(&value).unwrap(),
+            r#"{"a":{"op":">=","value":5},"b":{"op":"?=","value":{"c":{"op":"<","value":1}}},"d":[{"op":"==","value":2}],"e":{"NOT_EQUAL":1,"f":2}}"#
+        );
+
+        let (symbol, inner) = split_operator(value.get("a").unwrap());
+        assert_eq!(symbol, ">=");
+        assert_eq!(inner, &Value::Number(5.into()));
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::{anyhow, bail, Context};
+use jomini::json::DuplicateKeyMode;
+use std::{borrow::Cow, io::Write};
+
+use crate::{duplicate_keys::DuplicateKeyRules, value::Value};
+
+/// Structured formats that a document can be written out as
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    Yaml,
+    Toml,
+    MessagePack,
+    Cbor,
+}
+
+pub(crate) fn parse_output_format(s: &str) -> anyhow::Result<OutputFormat> {
+    match s.to_lowercase().as_str() {
+        "json" => Ok(OutputFormat::Json),
+        "yaml" | "yml" => Ok(OutputFormat::Yaml),
+        "toml" => Ok(OutputFormat::Toml),
+        "msgpack" | "messagepack" => Ok(OutputFormat::MessagePack),
+        "cbor" => Ok(OutputFormat::Cbor),
+        _ => Err(anyhow!("Unrecognized output format option")),
+    }
+}
+
+/// Serialize the document in the given format
+pub fn write_value<W: Write>(
+    value: &Value,
+    format: OutputFormat,
+    pretty: bool,
+    mut writer: W,
+) -> anyhow::Result<()> {
+    match format {
+        OutputFormat::Json if pretty => serde_json::to_writer_pretty(writer, value)?,
+        OutputFormat::Json => serde_json::to_writer(writer, value)?,
+        OutputFormat::Yaml => serde_yaml::to_writer(writer, &group_duplicate_keys(value))?,
+        OutputFormat::Toml => {
+            check_toml(value, "")?;
+            let out = toml::to_string(value).context("TOML cannot represent this document")?;
+            writer.write_all(out.as_bytes())?;
+        }
+        OutputFormat::MessagePack => {
+            rmp_serde::encode::write(&mut writer, &group_duplicate_keys(value))?
+        }
+        OutputFormat::Cbor => ciborium::into_writer(&group_duplicate_keys(value), &mut writer)?,
+    }
+
+    Ok(())
+}
+
+/// YAML, MessagePack, and CBOR maps have unique keys, and their parsers
+/// either reject or silently drop duplicates. So the duplicate keys that
+/// remain in a document (as in the default preserve mode) are grouped
+fn group_duplicate_keys(value: &Value) -> Cow<'_, Value> {
+    if has_duplicate_keys(value) {
+        let rules = DuplicateKeyRules::new(DuplicateKeyMode::Group);
+        Cow::Owned(rules.apply(value.clone()))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+fn has_duplicate_keys(value: &Value) -> bool {
+    match value {
+        Value::Array(values) => values.iter().any(has_duplicate_keys),
+        Value::Object(entries) => entries.iter().enumerate().any(|(i, (key, value))| {
+            entries[..i].iter().any(|(k, _)| k == key) || has_duplicate_keys(value)
+        }),
+        _ => false,
+    }
+}
+
+/// TOML is a strict subset of what the other formats can express, so reject
+/// the shapes it can't represent with an error that points to the culprit
+fn check_toml(value: &Value, path: &str) -> anyhow::Result<()> {
+    match value {
+        Value::Null => bail!("TOML cannot represent the null value at `{}`", path),
+        Value::Array(values) => {
+            for (i, value) in values.iter().enumerate() {
+                check_toml(value, &format!("{}[{}]", path, i))?;
+            }
+        }
+        Value::Object(entries) => {
+            for (i, (key, value)) in entries.iter().enumerate() {
+                let key_path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+
+                if entries[..i].iter().any(|(k, _)| k == key) {
+                    bail!(
+                        "TOML cannot represent the duplicate key `{}`, use a different --duplicate-keys mode",
+                        key_path
+                    );
+                }
+
+                check_toml(value, &key_path)?;
+            }
+        }
+        _ if path.is_empty() => bail!("TOML documents must be a table"),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(json: &str, format: OutputFormat) -> anyhow::Result<String> {
+        let value = Value::from_slice(json.as_bytes())?;
+        let mut out = Vec::new();
+        write_value(&value, format, false, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn test_yaml() {
+        let actual = convert(r#"{"a":"b","c":[1,2]}"#, OutputFormat::Yaml).unwrap();
+        assert_eq!(actual, "a: b\nc:\n- 1\n- 2\n");
+    }
+
+    #[test]
+    fn test_yaml_duplicate_keys() {
+        let actual = convert(r#"{"a":"b","a":1,"c":{"d":1,"d":[2]}}"#, OutputFormat::Yaml).unwrap();
+        assert_eq!(actual, "a:\n- b\n- 1\nc:\n  d:\n  - 1\n  - - 2\n");
+    }
+
+    #[test]
+    fn test_toml() {
+        let actual = convert(r#"{"a":"b","c":{"d":1}}"#, OutputFormat::Toml).unwrap();
+        assert_eq!(actual, "a = \"b\"\n\n[c]\nd = 1\n");
+    }
+
+    #[test]
+    fn test_toml_duplicate_keys() {
+        let err = convert(r#"{"a":{"b":1,"b":2}}"#, OutputFormat::Toml).unwrap_err();
+        assert!(err.to_string().contains("duplicate key `a.b`"));
+    }
+}
@@ -0,0 +1,335 @@
+use anyhow::Context;
+use argh::FromArgs;
+use jomini::json::{DuplicateKeyMode, JsonOptions};
+use serde_json::{json, Map};
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use crate::{
+    json::{parse_input_encoding, JsonSource},
+    value::Value,
+};
+
+/// infer a json schema describing the structure of saves or game files
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "schema")]
+pub(crate) struct SchemaCommand {
+    /// specify the format of generic (non-save) files: auto | utf-8 | windows-1252
+    #[argh(option, short = 'f', default = "String::from(\"auto\")")]
+    format: String,
+
+    /// strings with at most this many distinct values are listed as an enum
+    #[argh(option, default = "16")]
+    max_enum: usize,
+
+    /// files to infer the schema from
+    #[argh(positional)]
+    files: Vec<PathBuf>,
+}
+
+impl SchemaCommand {
+    pub(crate) fn exec(&self) -> anyhow::Result<i32> {
+        let encoding = parse_input_encoding(&self.format)?;
+        let mut shape = Shape::default();
+        for file in &self.files {
+            let data = std::fs::read(file)
+                .with_context(|| format!("Failed to read: {}", file.display()))?;
+            let source = JsonSource {
+                file,
+                encoding,
+                interpolation: false,
+                with_meta: false,
+                strip_formatting: false,
+                preserve_operators: false,
+                variables: None,
+                params: None,
+                mod_root: None,
+            };
+
+            // Preserve duplicate keys so that they can be detected
+            let options = JsonOptions::new().with_duplicate_keys(DuplicateKeyMode::Preserve);
+            let document = source
+                .to_value(&data, options)
+                .with_context(|| format!("Failed to parse: {}", file.display()))?;
+            shape.observe(&document, self.max_enum);
+        }
+
+        let mut schema = shape.schema();
+        if let serde_json::Value::Object(map) = &mut schema {
+            map.insert(
+                String::from("$schema"),
+                json!("https://json-schema.org/draft/2020-12/schema"),
+            );
+        }
+
+        let stdout = std::io::stdout();
+        let mut writer = BufWriter::new(stdout.lock());
+        serde_json::to_writer_pretty(&mut writer, &schema)?;
+        writer.write_all(b"\n")?;
+        Ok(0)
+    }
+}
+
+/// The observed structure of every value seen at a given location
+#[derive(Debug, Default)]
+struct Shape {
+    null: bool,
+    boolean: bool,
+    integer: bool,
+    number: bool,
+    string: bool,
+
+    /// distinct string values, None once there are too many to enumerate
+    strings: Option<BTreeSet<String>>,
+    items: Option<Box<Shape>>,
+    object: Option<ObjectShape>,
+}
+
+#[derive(Debug, Default)]
+struct ObjectShape {
+    /// number of objects observed
+    count: usize,
+    properties: Vec<Property>,
+
+    /// index of each key's property, as objects can have thousands of keys
+    indices: HashMap<String, usize>,
+
+    /// values of objects keyed by ids (eg: provinces), which are described
+    /// together instead of as individual properties
+    ids: Option<Box<Shape>>,
+}
+
+#[derive(Debug, Default)]
+struct Property {
+    key: String,
+    shape: Shape,
+
+    /// number of objects the key was seen in
+    present: usize,
+
+    /// the most times the key occurred within a single object
+    max_occurrences: usize,
+}
+
+impl Shape {
+    fn observe(&mut self, value: &Value, max_enum: usize) {
+        match value {
+            Value::Null => self.null = true,
+            Value::Bool(_) => self.boolean = true,
+            Value::Number(x) if x.is_f64() => self.number = true,
+            Value::Number(_) => self.integer = true,
+            Value::String(x) => {
+                if !self.string {
+                    self.strings = Some(BTreeSet::new());
+                }
+                self.string = true;
+                if let Some(strings) = self.strings.as_mut() {
+                    strings.insert(x.clone());
+                    if strings.len() > max_enum {
+                        self.strings = None;
+                    }
+                }
+            }
+            Value::Array(values) => {
+                let items = self.items.get_or_insert_with(Default::default);
+                for value in values {
+                    items.observe(value, max_enum);
+                }
+            }
+            Value::Object(entries) => {
+                let object = self.object.get_or_insert_with(Default::default);
+                object.observe(entries, max_enum);
+            }
+        }
+    }
+
+    fn schema(&self) -> serde_json::Value {
+        let mut types = Vec::new();
+        let mut schema = Map::new();
+
+        if self.null {
+            types.push("null");
+        }
+        if self.boolean {
+            types.push("boolean");
+        }
+        if self.number {
+            types.push("number");
+        } else if self.integer {
+            types.push("integer");
+        }
+        if self.string {
+            types.push("string");
+        }
+        if let Some(items) = &self.items {
+            types.push("array");
+            schema.insert(String::from("items"), items.schema());
+        }
+        if let Some(object) = &self.object {
+            types.push("object");
+            object.schema(&mut schema);
+        }
+
+        match types.as_slice() {
+            [] => {}
+            [x] => {
+                schema.insert(String::from("type"), json!(x));
+            }
+            _ => {
+                schema.insert(String::from("type"), json!(types));
+            }
+        }
+
+        // An enum applies to values of every type, so it can only list the
+        // strings when nothing else was seen
+        if let (["string"], Some(strings)) = (types.as_slice(), &self.strings) {
+            schema.insert(String::from("enum"), json!(strings));
+        }
+
+        serde_json::Value::Object(schema)
+    }
+}
+
+impl ObjectShape {
+    fn observe(&mut self, entries: &[(String, Value)], max_enum: usize) {
+        self.count += 1;
+
+        if !entries.is_empty() && entries.iter().all(|(key, _)| is_id(key)) {
+            let ids = self.ids.get_or_insert_with(Default::default);
+            for (_, value) in entries {
+                ids.observe(value, max_enum);
+            }
+            return;
+        }
+
+        let mut seen: HashMap<usize, usize> = HashMap::new();
+        for (key, value) in entries {
+            let index = match self.indices.get(key) {
+                Some(&index) => index,
+                None => {
+                    let index = self.properties.len();
+                    self.properties.push(Property {
+                        key: key.clone(),
+                        ..Default::default()
+                    });
+                    self.indices.insert(key.clone(), index);
+                    index
+                }
+            };
+
+            self.properties[index].shape.observe(value, max_enum);
+            *seen.entry(index).or_default() += 1;
+        }
+
+        for (index, occurrences) in seen {
+            let property = &mut self.properties[index];
+            property.present += 1;
+            property.max_occurrences = property.max_occurrences.max(occurrences);
+        }
+    }
+
+    fn schema(&self, schema: &mut Map<String, serde_json::Value>) {
+        let mut properties = Map::new();
+        let mut required = Vec::new();
+        for property in &self.properties {
+            let mut value = property.shape.schema();
+            if property.max_occurrences > 1 {
+                // Consumers converting with the group mode will see an array
+                if let serde_json::Value::Object(map) = &mut value {
+                    map.insert(String::from("x-duplicate-key"), json!(true));
+                    map.insert(
+                        String::from("x-max-occurrences"),
+                        json!(property.max_occurrences),
+                    );
+                }
+            }
+
+            properties.insert(property.key.clone(), value);
+            if property.present == self.count {
+                required.push(property.key.clone());
+            }
+        }
+
+        if !properties.is_empty() {
+            schema.insert(String::from("properties"), properties.into());
+        }
+        if !required.is_empty() {
+            schema.insert(String::from("required"), json!(required));
+        }
+        if let Some(ids) = &self.ids {
+            schema.insert(String::from("additionalProperties"), ids.schema());
+        }
+    }
+}
+
+/// Keys that identify entities rather than fields (eg: province ids and dates)
+fn is_id(key: &str) -> bool {
+    let key = key.strip_prefix('-').unwrap_or(key);
+    !key.is_empty() && key.bytes().all(|x| x.is_ascii_digit() || x == b'.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn infer(documents: &[&str]) -> serde_json::Value {
+        let mut shape = Shape::default();
+        for document in documents {
+            let value = Value::from_slice(document.as_bytes()).unwrap();
+            shape.observe(&value, 2);
+        }
+        shape.schema()
+    }
+
+    #[test]
+    fn test_optional_and_duplicate_keys() {
+        let schema = infer(&[r#"{"a":1,"b":"x","b":"y"}"#, r#"{"a":1.5,"c":[true]}"#]);
+        let expected = json!({
+            "type": "object",
+            "required": ["a"],
+            "properties": {
+                "a": {"type": "number"},
+                "b": {"type": "string", "enum": ["x", "y"], "x-duplicate-key": true, "x-max-occurrences": 2},
+                "c": {"type": "array", "items": {"type": "boolean"}},
+            }
+        });
+        assert_eq!(schema, expected);
+    }
+
+    #[test]
+    fn test_enum_of_mixed_types() {
+        let schema = infer(&[r#"{"a":"b","a":1}"#]);
+        let expected = json!({
+            "type": "object",
+            "required": ["a"],
+            "properties": {
+                "a": {"type": ["integer", "string"], "x-duplicate-key": true, "x-max-occurrences": 2},
+            }
+        });
+        assert_eq!(schema, expected);
+    }
+
+    #[test]
+    fn test_id_keyed_objects() {
+        let schema =
+            infer(&[r#"{"provinces":{"1":{"owner":"A"},"-2":{"owner":"B"},"3":{"owner":"C"}}}"#]);
+        let expected = json!({
+            "type": "object",
+            "required": ["provinces"],
+            "properties": {
+                "provinces": {
+                    "type": "object",
+                    "additionalProperties": {
+                        "type": "object",
+                        "required": ["owner"],
+                        "properties": {"owner": {"type": "string"}}
+                    }
+                }
+            }
+        });
+        assert_eq!(schema, expected);
+    }
+}
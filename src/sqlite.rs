@@ -0,0 +1,471 @@
+use anyhow::{bail, Context};
+use argh::FromArgs;
+use jomini::json::{DuplicateKeyMode, JsonOptions};
+use rusqlite::{params, params_from_iter, types::Value as SqlValue, Connection, OptionalExtension};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use crate::{
+    json::{parse_input_encoding, JsonSource},
+    table::{collection_rows, KEY_COLUMN},
+    value::Value,
+};
+
+/// export a save into a sqlite database, appending to it if it already exists
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "sqlite")]
+pub(crate) struct SqliteCommand {
+    /// path of the sqlite database to write to
+    #[argh(option, short = 'o')]
+    out: PathBuf,
+
+    /// specify the format of generic (non-save) files: auto | utf-8 | windows-1252
+    #[argh(option, short = 'f', default = "String::from(\"auto\")")]
+    format: String,
+
+    /// save file to export
+    #[argh(positional)]
+    file: PathBuf,
+}
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS saves (
+    _id INTEGER PRIMARY KEY,
+    file TEXT NOT NULL,
+    date TEXT UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS entries (
+    _save_id INTEGER NOT NULL REFERENCES saves(_id),
+    _table TEXT,
+    _row_id INTEGER,
+    path TEXT NOT NULL,
+    value
+);
+"#;
+
+/// Paths where each game stores the in-game date of the save
+const DATE_PATHS: [&str; 4] = [
+    "date",
+    "metadata.date",
+    "meta_data.meta_date",
+    "meta_data.game_date",
+];
+
+impl SqliteCommand {
+    pub(crate) fn exec(&self) -> anyhow::Result<i32> {
+        let data = std::fs::read(&self.file)
+            .with_context(|| format!("Failed to read: {}", self.file.display()))?;
+        let source = JsonSource {
+            file: &self.file,
+            encoding: parse_input_encoding(&self.format)?,
+            interpolation: false,
+            with_meta: false,
+            strip_formatting: false,
+            preserve_operators: false,
+            variables: None,
+            params: None,
+            mod_root: None,
+        };
+
+        let options = JsonOptions::new().with_duplicate_keys(DuplicateKeyMode::Group);
+        let document = source.to_value(&data, options)?;
+
+        let mut conn = Connection::open(&self.out)
+            .with_context(|| format!("Unable to open database: {}", self.out.display()))?;
+        let tx = conn.transaction()?;
+        let file_name = self.file.file_name().unwrap_or_default().to_string_lossy();
+        import(&tx, &file_name, &document)?;
+        tx.commit()?;
+        Ok(0)
+    }
+}
+
+/// Import a document into the database and return the id of the new save
+pub(crate) fn import(conn: &Connection, file: &str, document: &Value) -> anyhow::Result<i64> {
+    conn.execute_batch(SCHEMA)?;
+
+    let date = DATE_PATHS
+        .iter()
+        .find_map(|path| match document.lookup(path) {
+            Some(Value::String(x)) => Some(x.clone()),
+            _ => None,
+        });
+
+    if let Some(date) = date.as_deref() {
+        let existing: Option<i64> = conn
+            .query_row("SELECT _id FROM saves WHERE date = ?1", [date], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        if existing.is_some() {
+            bail!(
+                "A save dated {} has already been exported to the database",
+                date
+            );
+        }
+    }
+
+    conn.execute(
+        "INSERT INTO saves (file, date) VALUES (?1, ?2)",
+        params![file, date],
+    )?;
+
+    let mut importer = Importer {
+        conn,
+        save_id: conn.last_insert_rowid(),
+        columns: HashMap::new(),
+    };
+
+    let Some(entries) = document.as_object() else {
+        bail!("Expected the document to be an object");
+    };
+
+    let scope = Scope {
+        entity: None,
+        path: String::new(),
+    };
+    importer.walk(entries, &scope, false)?;
+    Ok(importer.save_id)
+}
+
+/// Where values are being imported from
+struct Scope<'a> {
+    /// table and row id of the entity that owns the values
+    entity: Option<(&'a str, i64)>,
+
+    /// dotted path relative to the entity (or the document root)
+    path: String,
+}
+
+struct Importer<'conn> {
+    conn: &'conn Connection,
+    save_id: i64,
+
+    /// known columns of each table, lowercased as sqlite names are case
+    /// insensitive
+    columns: HashMap<String, HashSet<String>>,
+}
+
+impl Importer<'_> {
+    fn walk(
+        &mut self,
+        entries: &[(String, Value)],
+        scope: &Scope,
+        entity_root: bool,
+    ) -> anyhow::Result<()> {
+        for (key, value) in entries {
+            let path = if scope.path.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", scope.path, key)
+            };
+
+            if is_collection(value) {
+                let table = match scope.entity {
+                    Some((parent, _)) => format!("{}_{}", parent, table_name(&path)),
+                    None => table_name(&path),
+                };
+
+                for (id, row) in collection_rows(value)? {
+                    let row_id = self.insert_entity(&table, scope.entity, &id, row)?;
+                    let scope = Scope {
+                        entity: Some((&table, row_id)),
+                        path: String::new(),
+                    };
+                    self.walk(row.as_object().unwrap_or_default(), &scope, true)?;
+                }
+                continue;
+            }
+
+            match value {
+                Value::Object(children) => {
+                    let scope = Scope {
+                        entity: scope.entity,
+                        path,
+                    };
+                    self.walk(children, &scope, false)?;
+                }
+
+                // Direct fields of an entity are stored as its columns
+                _ if entity_root => {}
+                _ => {
+                    let (table, row_id) = scope.entity.unzip();
+                    self.conn
+                        .prepare_cached(
+                            "INSERT INTO entries (_save_id, _table, _row_id, path, value) VALUES (?1, ?2, ?3, ?4, ?5)",
+                        )?
+                        .execute(params![self.save_id, table, row_id, path, sql_value(value)])?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn insert_entity(
+        &mut self,
+        table: &str,
+        parent: Option<(&str, i64)>,
+        id: &str,
+        entity: &Value,
+    ) -> anyhow::Result<i64> {
+        self.ensure_table(table, parent.map(|(x, _)| x))?;
+
+        let fields: Vec<_> = entity
+            .as_object()
+            .unwrap_or_default()
+            .iter()
+            .filter(|(_, value)| !matches!(value, Value::Object(_)) && !is_collection(value))
+            .collect();
+
+        let mut names = vec![
+            String::from("_save_id"),
+            String::from("_parent_id"),
+            String::from(KEY_COLUMN),
+        ];
+        let mut values = vec![
+            SqlValue::Integer(self.save_id),
+            parent.map_or(SqlValue::Null, |(_, x)| SqlValue::Integer(x)),
+            SqlValue::Text(id.to_owned()),
+        ];
+
+        for (key, value) in fields {
+            // Grouped duplicate keys can repeat the name of a column, and
+            // keys that only differ by case name the same column
+            let column = column_name(key);
+            if names.iter().any(|x| x.eq_ignore_ascii_case(&column)) {
+                continue;
+            }
+
+            self.ensure_column(table, &column)?;
+            names.push(column.into_owned());
+            values.push(sql_value(value));
+        }
+
+        let columns: Vec<_> = names.iter().map(|x| quote_identifier(x)).collect();
+        let placeholders: Vec<_> = (1..=names.len()).map(|i| format!("?{}", i)).collect();
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            quote_identifier(table),
+            columns.join(", "),
+            placeholders.join(", ")
+        );
+
+        self.conn
+            .prepare_cached(&sql)?
+            .execute(params_from_iter(values))?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    fn ensure_table(&mut self, table: &str, parent: Option<&str>) -> anyhow::Result<()> {
+        if self.columns.contains_key(table) {
+            return Ok(());
+        }
+
+        let parent_ref = parent
+            .map(|x| format!(" REFERENCES {}(_id)", quote_identifier(x)))
+            .unwrap_or_default();
+        self.conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                _id INTEGER PRIMARY KEY,
+                _save_id INTEGER NOT NULL REFERENCES saves(_id),
+                _parent_id INTEGER{},
+                {} TEXT
+            )",
+            quote_identifier(table),
+            parent_ref,
+            KEY_COLUMN
+        ))?;
+
+        // The table may exist from a previous export with more columns
+        let sql = format!("PRAGMA table_info({})", quote_identifier(table));
+        let mut stmt = self.conn.prepare(&sql)?;
+        let columns = stmt
+            .query_map([], |row| row.get::<_, String>(1))?
+            .map(|name| name.map(|x| x.to_ascii_lowercase()))
+            .collect::<Result<HashSet<_>, _>>()?;
+        self.columns.insert(table.to_owned(), columns);
+        Ok(())
+    }
+
+    fn ensure_column(&mut self, table: &str, column: &str) -> anyhow::Result<()> {
+        let columns = self.columns.entry(table.to_owned()).or_default();
+        if columns.contains(&column.to_ascii_lowercase()) {
+            return Ok(());
+        }
+
+        self.conn.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {}",
+            quote_identifier(table),
+            quote_identifier(column)
+        ))?;
+        columns.insert(column.to_ascii_lowercase());
+        Ok(())
+    }
+}
+
+/// A collection of entities is either an array of objects (often from grouped
+/// duplicate keys) or an object where every value is an entity keyed by id.
+fn is_collection(value: &Value) -> bool {
+    match value {
+        Value::Array(values) => {
+            !values.is_empty() && values.iter().all(|x| matches!(x, Value::Object(_)))
+        }
+        Value::Object(entries) => {
+            !entries.is_empty()
+                && entries.iter().all(|(_, x)| match x {
+                    Value::Object(_) => true,
+                    Value::Array(_) => is_collection(x),
+                    _ => false,
+                })
+        }
+        _ => false,
+    }
+}
+
+/// The column of an entity field. Fields that start with an underscore get
+/// another one so that they can't clash with the generated columns (`_id`,
+/// `_save_id`, `_parent_id`, and `_key`)
+fn column_name(key: &str) -> Cow<'_, str> {
+    if key.starts_with('_') {
+        Cow::Owned(format!("_{}", key))
+    } else {
+        Cow::Borrowed(key)
+    }
+}
+
+fn table_name(path: &str) -> String {
+    path.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn sql_value(value: &Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(x) => SqlValue::Integer(i64::from(*x)),
+        Value::Number(x) => match x.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => x.as_f64().map_or(SqlValue::Null, SqlValue::Real),
+        },
+        Value::String(x) => SqlValue::Text(x.clone()),
+        Value::Array(_) | Value::Object(_) => {
+            SqlValue::Text(serde_json::to_string(value).unwrap_or_default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import() {
+        let data = r#"{
+            "date":"1444.11.11",
+            "speed":2,
+            "provinces":{
+                "-1":{"owner":"SWE","cores":["SWE"],"history":{"1444.11.11":{"owner":"SWE"},"1450.1.1":{"owner":"DAN"}}},
+                "-2":{"owner":"DAN","base_tax":3.5}
+            }
+        }"#;
+        let document = Value::from_slice(data.as_bytes()).unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        let save_id = import(&conn, "test.eu4", &document).unwrap();
+
+        let owners: Vec<(String, String)> = conn
+            .prepare("SELECT _key, owner FROM provinces ORDER BY _id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            owners,
+            vec![
+                (String::from("-1"), String::from("SWE")),
+                (String::from("-2"), String::from("DAN"))
+            ]
+        );
+
+        let history: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM provinces_history h JOIN provinces p ON h._parent_id = p._id WHERE p._key = '-1'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(history, 2);
+
+        let speed: i64 = conn
+            .query_row(
+                "SELECT value FROM entries WHERE _save_id = ?1 AND path = 'speed'",
+                [save_id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(speed, 2);
+
+        // The same save can't be exported twice
+        assert!(import(&conn, "test.eu4", &document).is_err());
+    }
+
+    #[test]
+    fn test_import_reserved_fields() {
+        let data = r#"{
+            "date":"1444.11.11",
+            "provinces":{
+                "-1":{"id":1,"_id":2,"_key":"a","_PARENT_ID":3,"__key":"b","history":{"1450.1.1":{"_save_id":4}}}
+            }
+        }"#;
+        let document = Value::from_slice(data.as_bytes()).unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        import(&conn, "test.eu4", &document).unwrap();
+
+        let row: (String, i64, i64, String, i64, String) = conn
+            .query_row(
+                "SELECT _key, id, __id, __key, __parent_id, ___key FROM provinces",
+                [],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                    ))
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            row,
+            (
+                String::from("-1"),
+                1,
+                2,
+                String::from("a"),
+                3,
+                String::from("b")
+            )
+        );
+
+        let history: (i64, i64) = conn
+            .query_row(
+                "SELECT h._save_id, h.__save_id FROM provinces_history h JOIN provinces p ON h._parent_id = p._id",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(history, (1, 4));
+    }
+}
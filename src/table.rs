@@ -0,0 +1,184 @@
+use anyhow::{bail, Context};
+use argh::FromArgs;
+use jomini::json::{DuplicateKeyMode, JsonOptions};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use crate::{
+    json::{parse_input_encoding, JsonSource},
+    value::Value,
+};
+
+/// Name of the column holding the key (or index) of each entity, prefixed so
+/// that it doesn't clash with an `id` field of the entities
+pub(crate) const KEY_COLUMN: &str = "_key";
+
+/// export a collection of entities (eg: provinces) as a csv table
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "table")]
+pub(crate) struct TableCommand {
+    /// dotted path to the collection of entities, eg: provinces
+    #[argh(option, short = 'p')]
+    path: String,
+
+    /// comma separated columns with nested fields separated by dots (eg:
+    /// owner,history.owner). Omission includes every scalar field
+    #[argh(option, short = 'c')]
+    columns: Option<String>,
+
+    /// write tab separated values instead of csv
+    #[argh(switch)]
+    tsv: bool,
+
+    /// specify the format of generic (non-save) files: auto | utf-8 | windows-1252
+    #[argh(option, short = 'f', default = "String::from(\"auto\")")]
+    format: String,
+
+    /// output the table to the given file instead of stdout
+    #[argh(option, short = 'o')]
+    out: Option<PathBuf>,
+
+    /// save or game file to read
+    #[argh(positional)]
+    file: PathBuf,
+}
+
+impl TableCommand {
+    pub(crate) fn exec(&self) -> anyhow::Result<i32> {
+        let data = std::fs::read(&self.file)
+            .with_context(|| format!("Failed to read: {}", self.file.display()))?;
+        let source = JsonSource {
+            file: &self.file,
+            encoding: parse_input_encoding(&self.format)?,
+            interpolation: false,
+            with_meta: false,
+            strip_formatting: false,
+            preserve_operators: false,
+            variables: None,
+            params: None,
+            mod_root: None,
+        };
+
+        let options = JsonOptions::new().with_duplicate_keys(DuplicateKeyMode::Group);
+        let document = source.to_value(&data, options)?;
+        let collection = document
+            .lookup(&self.path)
+            .with_context(|| format!("Path not found: {}", self.path))?;
+        let rows = collection_rows(collection)?;
+
+        let columns: Vec<String> = match self.columns.as_deref() {
+            Some(columns) => columns.split(',').map(|x| x.trim().to_owned()).collect(),
+            None => scalar_columns(&rows),
+        };
+
+        let writer: Box<dyn Write> = match self.out.as_deref() {
+            Some(path) => {
+                let file = File::create(path)
+                    .with_context(|| format!("Unable to create file: {}", path.display()))?;
+                Box::new(BufWriter::new(file))
+            }
+            None => Box::new(BufWriter::new(std::io::stdout().lock())),
+        };
+
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(if self.tsv { b'\t' } else { b',' })
+            .from_writer(writer);
+
+        let header = std::iter::once(KEY_COLUMN).chain(columns.iter().map(|x| x.as_str()));
+        writer.write_record(header)?;
+        for (id, row) in rows {
+            let cells = columns.iter().map(|column| cell(row.lookup(column)));
+            writer.write_record(std::iter::once(id).chain(cells))?;
+        }
+
+        writer.flush()?;
+        Ok(0)
+    }
+}
+
+/// Each child object of the collection is a row. Collections are either
+/// objects keyed by id or arrays, where the index serves as the id.
+pub(crate) fn collection_rows(collection: &Value) -> anyhow::Result<Vec<(String, &Value)>> {
+    let mut rows = Vec::new();
+    match collection {
+        Value::Object(entries) => {
+            for (key, value) in entries {
+                match value {
+                    Value::Object(_) => rows.push((key.clone(), value)),
+
+                    // Grouped duplicate ids
+                    Value::Array(values) => rows.extend(
+                        values
+                            .iter()
+                            .filter(|x| matches!(x, Value::Object(_)))
+                            .map(|x| (key.clone(), x)),
+                    ),
+                    _ => {}
+                }
+            }
+        }
+        Value::Array(values) => rows.extend(
+            values
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| matches!(x, Value::Object(_)))
+                .map(|(i, x)| (i.to_string(), x)),
+        ),
+        _ => bail!("Path does not point to a collection of entities"),
+    }
+
+    Ok(rows)
+}
+
+/// The union of scalar fields across all rows in the order they are first seen
+fn scalar_columns(rows: &[(String, &Value)]) -> Vec<String> {
+    let mut columns: Vec<String> = Vec::new();
+    for (_, row) in rows {
+        for (key, value) in row.as_object().unwrap_or_default() {
+            let scalar = !matches!(value, Value::Object(_) | Value::Array(_));
+            if scalar && !columns.contains(key) {
+                columns.push(key.clone());
+            }
+        }
+    }
+    columns
+}
+
+/// Format a value as a table cell. Containers are written as json
+pub(crate) fn cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(x)) => x.clone(),
+        Some(Value::Number(x)) => x.to_string(),
+        Some(Value::Bool(x)) => x.to_string(),
+        Some(x) => serde_json::to_string(x).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collection_rows() {
+        let data = r#"{"-1":{"owner":"SWE"},"-2":[{"owner":"DAN"},{"owner":"NOR"}],"dummy":1}"#;
+        let value = Value::from_slice(data.as_bytes()).unwrap();
+        let rows = collection_rows(&value).unwrap();
+        let actual: Vec<_> = rows
+            .iter()
+            .map(|(id, row)| (id.as_str(), cell(row.get("owner"))))
+            .collect();
+        assert_eq!(
+            actual,
+            vec![
+                ("-1", String::from("SWE")),
+                ("-2", String::from("DAN")),
+                ("-2", String::from("NOR"))
+            ]
+        );
+        assert_eq!(scalar_columns(&rows), vec![String::from("owner")]);
+    }
+}
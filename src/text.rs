@@ -0,0 +1,364 @@
+use anyhow::{anyhow, bail, Context};
+use argh::FromArgs;
+use jomini::json::DuplicateKeyMode;
+use std::{
+    fs,
+    io::{stdin, BufWriter, Read, Write},
+    path::PathBuf,
+};
+
+use crate::{
+    json::{parse_duplicate_keys, parse_encoding, Encoding},
+    operators::split_operator,
+    value::Value,
+};
+
+/// convert json back into game script (the inverse of the json command)
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "text")]
+pub(crate) struct TextCommand {
+    /// specify the encoding of the output: utf-8 | windows-1252
+    #[argh(option, short = 'f', default = "String::from(\"windows-1252\")")]
+    format: String,
+
+    /// specify how duplicate keys were encoded: preserve | group | key-value-pairs
+    #[argh(option, short = 'k', default = "String::from(\"preserve\")")]
+    duplicate_keys: String,
+
+    /// json file to convert. Omission reads from stdin
+    #[argh(positional)]
+    file: Option<PathBuf>,
+}
+
+impl TextCommand {
+    pub(crate) fn exec(&self) -> anyhow::Result<i32> {
+        let encoding = parse_encoding(&self.format)?;
+        let keys = parse_duplicate_keys(&self.duplicate_keys)?;
+
+        let data = match self.file.as_deref() {
+            Some(path) => {
+                fs::read(path).with_context(|| format!("Failed to read: {}", path.display()))?
+            }
+            None => {
+                let mut buf = Vec::new();
+                stdin().read_to_end(&mut buf)?;
+                buf
+            }
+        };
+
+        let value = Value::from_slice(&data).context("Failed to parse json")?;
+        let stdout = std::io::stdout();
+        let writer = BufWriter::new(stdout.lock());
+        let mut writer = ScriptWriter::new(writer, encoding, keys);
+        writer.write_document(&value)?;
+        writer.into_inner().flush()?;
+        Ok(0)
+    }
+}
+
+/// Keys that jomini writes for header tokens like `color = rgb { 10 20 30 }`
+const HEADERS: [&str; 3] = ["rgb", "hsv", "hsv360"];
+
+/// Writes a json document out as game script
+pub struct ScriptWriter<W> {
+    writer: W,
+    encoding: Encoding,
+    mode: DuplicateKeyMode,
+    depth: usize,
+}
+
+impl<W: Write> ScriptWriter<W> {
+    pub fn new(writer: W, encoding: Encoding, mode: DuplicateKeyMode) -> Self {
+        ScriptWriter {
+            writer,
+            encoding,
+            mode,
+            depth: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Write a document whose root must be an object
+    pub fn write_document(&mut self, value: &Value) -> anyhow::Result<()> {
+        match self.object_entries(value) {
+            Some(entries) => self.write_entries(&entries),
+            None => bail!("Expected the json document to be an object"),
+        }
+    }
+
+    /// Returns the entries of an object, accounting for the key-value-pairs
+    /// representation
+    fn object_entries<'a>(&self, value: &'a Value) -> Option<Vec<(&'a str, &'a Value)>> {
+        if matches!(self.mode, DuplicateKeyMode::KeyValuePairs) {
+            let Some(Value::Array(pairs)) = kv_container(value, "obj") else {
+                return None;
+            };
+
+            return pairs
+                .iter()
+                .map(|pair| match pair {
+                    Value::Array(kv) if kv.len() == 2 => match &kv[0] {
+                        Value::String(key) => Some((key.as_str(), &kv[1])),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect();
+        }
+
+        value
+            .as_object()
+            .map(|entries| entries.iter().map(|(k, v)| (k.as_str(), v)).collect())
+    }
+
+    /// Returns the elements of an array, accounting for the key-value-pairs
+    /// representation
+    fn array_values<'a>(&self, value: &'a Value) -> Option<&'a [Value]> {
+        let value = if matches!(self.mode, DuplicateKeyMode::KeyValuePairs) {
+            kv_container(value, "array")?
+        } else {
+            value
+        };
+
+        match value {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    fn write_entries(&mut self, entries: &[(&str, &Value)]) -> anyhow::Result<()> {
+        for (key, value) in entries {
+            // In group mode, duplicate keys were collapsed into an array, so
+            // `a=b a=1` became `"a": ["b", 1]`. That can't be distinguished
+            // from a genuine array like `a={ b 1 }`, so the former
+            // interpretation is favored to round trip duplicate keys
+            if matches!(self.mode, DuplicateKeyMode::Group) {
+                if let Value::Array(values) = value {
+                    if !values.is_empty() {
+                        for value in values {
+                            self.write_entry(key, value)?;
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            self.write_entry(key, value)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_entry(&mut self, key: &str, value: &Value) -> anyhow::Result<()> {
+        self.write_indent()?;
+        self.write_scalar(key)?;
+
+        let (op, value) = split_operator(value);
+        self.write_raw(op)?;
+        self.write_value(value)?;
+        self.write_raw("\n")
+    }
+
+    fn write_value(&mut self, value: &Value) -> anyhow::Result<()> {
+        if let Some(entries) = self.object_entries(value) {
+            if let [(header, inner)] = entries.as_slice() {
+                if HEADERS.contains(header) && self.array_values(inner).is_some() {
+                    self.write_raw(header)?;
+                    self.write_raw(" ")?;
+                    return self.write_value(inner);
+                }
+            }
+
+            self.write_raw("{\n")?;
+            self.depth += 1;
+            self.write_entries(&entries)?;
+            self.depth -= 1;
+            self.write_indent()?;
+            return self.write_raw("}");
+        }
+
+        if let Some(values) = self.array_values(value) {
+            let nested = values
+                .iter()
+                .any(|x| self.object_entries(x).is_some() || self.array_values(x).is_some());
+
+            if !nested {
+                self.write_raw("{ ")?;
+                for value in values {
+                    self.write_value(value)?;
+                    self.write_raw(" ")?;
+                }
+                return self.write_raw("}");
+            }
+
+            self.write_raw("{\n")?;
+            self.depth += 1;
+            for value in values {
+                self.write_indent()?;
+                self.write_value(value)?;
+                self.write_raw("\n")?;
+            }
+            self.depth -= 1;
+            self.write_indent()?;
+            return self.write_raw("}");
+        }
+
+        match value {
+            Value::Null => self.write_raw("\"\""),
+            Value::Bool(true) => self.write_raw("yes"),
+            Value::Bool(false) => self.write_raw("no"),
+            Value::Number(x) => self.write_raw(&x.to_string()),
+            Value::String(x) => self.write_scalar(x),
+            Value::Array(_) | Value::Object(_) => Err(anyhow!(
+                "Unexpected container shape for the duplicate key mode"
+            )),
+        }
+    }
+
+    /// Write a string, quoting it if the game would otherwise misinterpret it
+    fn write_scalar(&mut self, s: &str) -> anyhow::Result<()> {
+        if !needs_quotes(s) {
+            return self.write_raw(s);
+        }
+
+        let mut escaped = String::with_capacity(s.len() + 2);
+        escaped.push('"');
+        for c in s.chars() {
+            if matches!(c, '"' | '\\') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped.push('"');
+        self.write_raw(&escaped)
+    }
+
+    fn write_indent(&mut self) -> anyhow::Result<()> {
+        for _ in 0..self.depth {
+            self.writer.write_all(b"\t")?;
+        }
+        Ok(())
+    }
+
+    fn write_raw(&mut self, s: &str) -> anyhow::Result<()> {
+        match self.encoding {
+            Encoding::Utf8 => self.writer.write_all(s.as_bytes())?,
+            Encoding::Windows1252 => self.writer.write_all(&encode_windows1252(s))?,
+        }
+        Ok(())
+    }
+}
+
+/// Unwrap the `{"type": .., "val": ..}` container of the key-value-pairs mode
+fn kv_container<'a>(value: &'a Value, kind: &str) -> Option<&'a Value> {
+    match (value.get("type"), value.get("val")) {
+        (Some(Value::String(x)), Some(val)) if x == kind => Some(val),
+        _ => None,
+    }
+}
+
+fn needs_quotes(s: &str) -> bool {
+    // Strings that look like numbers or booleans must have been quoted in the
+    // source, otherwise they would have been written as json numbers or bools
+    s.is_empty()
+        || s.parse::<f64>().is_ok()
+        || matches!(s, "yes" | "no")
+        || s.chars().any(|c| {
+            c.is_whitespace()
+                || c.is_control()
+                || matches!(
+                    c,
+                    '=' | '{' | '}' | '"' | '#' | '<' | '>' | '!' | '?' | '\\'
+                )
+        })
+}
+
+/// Encode a string as windows-1252, replacing unrepresentable characters
+pub(crate) fn encode_windows1252(s: &str) -> Vec<u8> {
+    s.chars()
+        .map(|c| match c as u32 {
+            x @ (0x00..=0x7f | 0xa0..=0xff) => x as u8,
+            0x20ac => 0x80,
+            0x201a => 0x82,
+            0x0192 => 0x83,
+            0x201e => 0x84,
+            0x2026 => 0x85,
+            0x2020 => 0x86,
+            0x2021 => 0x87,
+            0x02c6 => 0x88,
+            0x2030 => 0x89,
+            0x0160 => 0x8a,
+            0x2039 => 0x8b,
+            0x0152 => 0x8c,
+            0x017d => 0x8e,
+            0x2018 => 0x91,
+            0x2019 => 0x92,
+            0x201c => 0x93,
+            0x201d => 0x94,
+            0x2022 => 0x95,
+            0x2013 => 0x96,
+            0x2014 => 0x97,
+            0x02dc => 0x98,
+            0x2122 => 0x99,
+            0x0161 => 0x9a,
+            0x203a => 0x9b,
+            0x0153 => 0x9c,
+            0x017e => 0x9e,
+            0x0178 => 0x9f,
+            x @ 0x80..=0x9f => x as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_script(json: &str, mode: DuplicateKeyMode) -> String {
+        let value = Value::from_slice(json.as_bytes()).unwrap();
+        let mut writer = ScriptWriter::new(Vec::new(), Encoding::Utf8, mode);
+        writer.write_document(&value).unwrap();
+        String::from_utf8(writer.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn test_preserve() {
+        let json =
+            r#"{"a":"b","a":1,"name":"Two Words","id":"10","obj":{"flag":true,"list":[1,2]}}"#;
+        let expected =
+            "a=b\na=1\nname=\"Two Words\"\nid=\"10\"\nobj={\n\tflag=yes\n\tlist={ 1 2 }\n}\n";
+        assert_eq!(to_script(json, DuplicateKeyMode::Preserve), expected);
+    }
+
+    #[test]
+    fn test_group() {
+        let json = r#"{"history":[{"owner":"FRA"},{"owner":"ENG"}],"a":["b",1],"empty":[]}"#;
+        let expected =
+            "history={\n\towner=FRA\n}\nhistory={\n\towner=ENG\n}\na=b\na=1\nempty={ }\n";
+        assert_eq!(to_script(json, DuplicateKeyMode::Group), expected);
+    }
+
+    #[test]
+    fn test_key_value_pairs() {
+        let json = r#"{"type":"obj","val":[["a","b"],["a",1],["c",{"type":"array","val":[1,2]}]]}"#;
+        let expected = "a=b\na=1\nc={ 1 2 }\n";
+        assert_eq!(to_script(json, DuplicateKeyMode::KeyValuePairs), expected);
+    }
+
+    #[test]
+    fn test_operators_and_headers() {
+        let json = r#"{"age":{"GREATER_THAN_EQUAL":16},"color":{"rgb":[10,20,30]}}"#;
+        let expected = "age>=16\ncolor=rgb { 10 20 30 }\n";
+        assert_eq!(to_script(json, DuplicateKeyMode::Preserve), expected);
+    }
+
+    #[test]
+    fn test_windows1252_encoding() {
+        assert_eq!(encode_windows1252("Zürich €"), b"Z\xfcrich \x80".to_vec());
+        assert_eq!(encode_windows1252("日"), b"?".to_vec());
+    }
+}
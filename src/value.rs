@@ -0,0 +1,249 @@
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    ser::{SerializeMap, SerializeSeq},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{cmp::Ordering, fmt};
+
+/// An owned JSON document.
+///
+/// Unlike `serde_json::Value`, objects are a list of entries so that
+/// duplicate keys (as written by `DuplicateKeyMode::Preserve`) and their
+/// order survive a round trip.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(serde_json::Number),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Parse a JSON document while keeping duplicate keys
+    pub fn from_slice(data: &[u8]) -> serde_json::Result<Value> {
+        serde_json::from_slice(data)
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, Value)]> {
+        match self {
+            Value::Object(entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    /// Returns the first value associated with the key
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_object()?
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    /// Look up a value by a dotted path (eg: `history.owner`). Keys may
+    /// themselves contain dots (eg: dates), so the longest matching key at each
+    /// level is preferred
+    pub fn lookup(&self, path: &str) -> Option<&Value> {
+        if path.is_empty() {
+            return Some(self);
+        }
+
+        let entries = self.as_object()?;
+        let mut candidates: Vec<_> = entries
+            .iter()
+            .filter(|(key, _)| {
+                path == key
+                    || (path.starts_with(key.as_str()) && path[key.len()..].starts_with('.'))
+            })
+            .collect();
+        candidates.sort_by_key(|(key, _)| std::cmp::Reverse(key.len()));
+
+        candidates.into_iter().find_map(|(key, value)| {
+            let rest = path[key.len()..].trim_start_matches('.');
+            value.lookup(rest)
+        })
+    }
+
+    /// Rewrite the document into a canonical form so that semantically equal
+    /// documents serialize identically: object keys are sorted (ids and dates
+    /// numerically) and floats without a fractional part become integers.
+    /// Sorting is stable so duplicate keys keep their relative order
+    pub fn canonicalize(&mut self) {
+        match self {
+            Value::Number(x) => {
+                if let Some(f) = x.as_f64().filter(|_| x.is_f64()) {
+                    if f.fract() == 0.0 && f.abs() < i64::MAX as f64 {
+                        *x = (f as i64).into();
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(Value::canonicalize),
+            Value::Object(entries) => {
+                entries.sort_by(|(a, _), (b, _)| canonical_key_order(a, b));
+                entries.iter_mut().for_each(|(_, v)| v.canonicalize());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Order keys that are integers or dotted integers (eg: `1444.11.11`) by
+/// their numeric segments ahead of all other keys, which are ordered as text
+fn canonical_key_order(a: &str, b: &str) -> Ordering {
+    fn segments(key: &str) -> Option<Vec<i64>> {
+        key.split('.').map(|x| x.parse().ok()).collect()
+    }
+
+    match (segments(a), segments(b)) {
+        (Some(x), Some(y)) => x.cmp(&y).then_with(|| a.cmp(b)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.cmp(b),
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Value::Null => serializer.serialize_unit(),
+            Value::Bool(x) => serializer.serialize_bool(*x),
+            Value::Number(x) => x.serialize(serializer),
+            Value::String(x) => serializer.serialize_str(x),
+            Value::Array(values) => {
+                let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            Value::Object(entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D>(deserializer: D) -> Result<Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any valid JSON value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+        Ok(serde_json::Number::from_f64(v).map_or(Value::Null, Value::Number))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::String(v.to_owned()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Value, E> {
+        Ok(Value::String(v))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Deserialize::deserialize(deserializer)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(Value::Array(values))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some(entry) = map.next_entry::<String, Value>()? {
+            entries.push(entry);
+        }
+        Ok(Value::Object(entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_keys_round_trip() {
+        let data = r#"{"a":"b","a":1,"c":[true,null,1.5]}"#;
+        let value = Value::from_slice(data.as_bytes()).unwrap();
+        assert_eq!(value.get("a"), Some(&Value::String(String::from("b"))));
+        assert_eq!(serde_json::to_string(&value).unwrap(), data);
+    }
+
+    #[test]
+    fn test_lookup_dotted_keys() {
+        let data = r#"{"history":{"owner":"SWE","1444.11.11":{"owner":"DAN"}}}"#;
+        let value = Value::from_slice(data.as_bytes()).unwrap();
+        let owner = |path| match value.lookup(path) {
+            Some(Value::String(x)) => Some(x.as_str()),
+            _ => None,
+        };
+        assert_eq!(owner("history.owner"), Some("SWE"));
+        assert_eq!(owner("history.1444.11.11.owner"), Some("DAN"));
+        assert_eq!(owner("history.1444.owner"), None);
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let data = r#"{"b":2.0,"10":{"y":1.5,"x":-0.0},"a":1,"2":[3.000],"1444.11.11":1,"1444.2.1":2,"a":0}"#;
+        let mut value = Value::from_slice(data.as_bytes()).unwrap();
+        value.canonicalize();
+        assert_eq!(
+            serde_json::to_string(&value).unwrap(),
+            r#"{"2":[3],"10":{"x":0,"y":1.5},"1444.2.1":2,"1444.11.11":1,"a":1,"a":0,"b":2}"#
+        );
+    }
+}
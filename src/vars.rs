@@ -0,0 +1,210 @@
+use anyhow::Context;
+use argh::FromArgs;
+use jomini::TextTape;
+use serde_json::json;
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+use crate::{
+    inline_scripts::expand_inline_scripts,
+    interpolation::{InterpolatedTape, InterpolationOptions, Location, VariableValue},
+    json::{load_variables, parse_input_encoding, parse_params, resolve_encoding, UTF8_BOM},
+};
+
+/// list the variables declared in a game file, their values, and their uses
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "vars")]
+pub(crate) struct VarsCommand {
+    /// specify the encoding of the input: auto | utf-8 | windows-1252
+    #[argh(option, short = 'f', default = "String::from(\"auto\")")]
+    format: String,
+
+    /// file or directory of scripted variables to load before interpolating,
+    /// may be repeated with later files overriding earlier ones
+    #[argh(option)]
+    vars_from: Vec<PathBuf>,
+
+    /// parameter of scripted effects and triggers as `NAME=VALUE` that
+    /// replaces `$NAME$` and selects `[[NAME]` blocks, may be repeated
+    #[argh(option, long = "param")]
+    params: Vec<String>,
+
+    /// root directory of the mod that `inline_script` entries are read from
+    /// (`common/inline_scripts`) and expanded
+    #[argh(option)]
+    mod_root: Option<PathBuf>,
+
+    /// write the report as json
+    #[argh(switch)]
+    json: bool,
+
+    /// file to inspect
+    #[argh(positional)]
+    file: PathBuf,
+}
+
+impl VarsCommand {
+    pub(crate) fn exec(&self) -> anyhow::Result<i32> {
+        let encoding = parse_input_encoding(&self.format)?;
+        let variables = load_variables(&self.vars_from, encoding)?;
+        let params = parse_params(&self.params)?;
+        let data = fs::read(&self.file)
+            .with_context(|| format!("Failed to read: {}", self.file.display()))?;
+        let encoding = resolve_encoding(encoding, &self.file, &data);
+        let body = data.strip_prefix(UTF8_BOM).unwrap_or(&data);
+        let expansion = expand_inline_scripts(body, self.mod_root.as_deref())?;
+        let splices = expansion.splices();
+        let original = body;
+        let body = expansion.data();
+
+        // Errors are part of the report rather than a reason to stop
+        let tape = TextTape::from_slice(body)?;
+        let name = self.file.display().to_string();
+        let mut options = InterpolationOptions::new(encoding)
+            .with_variables(variables)
+            .with_source(&name, body)
+            .with_splices(original, &splices)
+            .with_collected_errors();
+        if let Some(params) = params {
+            options = options.with_params(params);
+        }
+        let interpolated = InterpolatedTape::from_tape_with_options(&tape, options)
+            .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+
+        let stdout = io::stdout();
+        let mut writer = BufWriter::new(stdout.lock());
+        if self.json {
+            serde_json::to_writer_pretty(&mut writer, &report(&name, &interpolated))?;
+            writer.write_all(b"\n")?;
+        } else {
+            write_report(&mut writer, &name, &interpolated)?;
+        }
+        writer.flush()?;
+
+        if !self.json {
+            for error in interpolated.errors() {
+                eprintln!("error: {}\n", error);
+            }
+        }
+
+        Ok(0)
+    }
+}
+
+/// Write a line per declaration and undefined variable, prefixed with its
+/// location in the file (or the inline script that it was expanded from):
+///
+/// ```text
+/// units.txt:2:1: @scale = 10 (used 2 times)
+/// units.txt:3:1: @double = 20 from @[scale * 2] (used 1 time)
+/// units.txt:4:1: @spare = 5 (unused)
+/// units.txt:6:9: @missing is undefined
+/// ```
+fn write_report<W: Write>(
+    mut writer: W,
+    name: &str,
+    interpolated: &InterpolatedTape,
+) -> io::Result<()> {
+    for declaration in interpolated.variable_declarations() {
+        let position = position(
+            declaration.file.as_deref().unwrap_or(name),
+            declaration.location,
+        );
+        let value = match &declaration.value {
+            Some(value) => display_value(value),
+            None => String::from("<error>"),
+        };
+
+        write!(writer, "{}: @{} = {}", position, declaration.name, value)?;
+        if value != declaration.definition {
+            write!(writer, " from {}", declaration.definition)?;
+        }
+
+        match declaration.uses {
+            0 => writeln!(writer, " (unused)")?,
+            1 => writeln!(writer, " (used 1 time)")?,
+            uses => writeln!(writer, " (used {} times)", uses)?,
+        }
+    }
+
+    for reference in interpolated.undefined_variables() {
+        let position = position(
+            reference.file.as_deref().unwrap_or(name),
+            reference.location,
+        );
+        writeln!(writer, "{}: @{} is undefined", position, reference.name)?;
+    }
+
+    Ok(())
+}
+
+fn position(name: &str, location: Option<Location>) -> String {
+    match location {
+        Some(location) => format!("{}:{}", name, location),
+        None => name.to_owned(),
+    }
+}
+
+/// Text is quoted to distinguish it from numbers, as it is declared
+fn display_value(value: &VariableValue) -> String {
+    match value {
+        VariableValue::Number(_) => value.to_string(),
+        VariableValue::Text(x) => format!("\"{}\"", x),
+    }
+}
+
+fn report(name: &str, interpolated: &InterpolatedTape) -> serde_json::Value {
+    let location = |location: Option<Location>| match location {
+        Some(x) => (json!(x.line), json!(x.column)),
+        None => (json!(null), json!(null)),
+    };
+
+    let variables: Vec<_> = interpolated
+        .variable_declarations()
+        .iter()
+        .map(|declaration| {
+            let (line, column) = location(declaration.location);
+            let value = match &declaration.value {
+                Some(VariableValue::Number(x)) if x.fract() == 0.0 => json!(*x as i64),
+                Some(VariableValue::Number(x)) => json!(x),
+                Some(VariableValue::Text(x)) => json!(x),
+                None => json!(null),
+            };
+
+            json!({
+                "name": declaration.name,
+                "value": value,
+                "definition": declaration.definition,
+                "file": declaration.file.as_deref().unwrap_or(name),
+                "line": line,
+                "column": column,
+                "root": declaration.root,
+                "uses": declaration.uses,
+                "unused": declaration.uses == 0,
+            })
+        })
+        .collect();
+
+    let undefined: Vec<_> = interpolated
+        .undefined_variables()
+        .iter()
+        .map(|reference| {
+            let (line, column) = location(reference.location);
+            json!({
+                "name": reference.name,
+                "file": reference.file.as_deref().unwrap_or(name),
+                "line": line,
+                "column": column,
+            })
+        })
+        .collect();
+
+    json!({
+        "variables": variables,
+        "undefined": undefined,
+        "errors": interpolated.errors(),
+    })
+}
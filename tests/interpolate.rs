@@ -0,0 +1,154 @@
+use assert_cmd::Command;
+use std::path::Path;
+
+#[test]
+fn test_interpolate() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("interpolate")
+        .arg("--vars-from")
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("scripted_variables"),
+        )
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("interpolation.txt"),
+        )
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!("position={\n\tx=25\n\ty=40\n}\n", actual);
+}
+
+#[test]
+fn test_interpolate_params() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("interpolate")
+        .arg("--param")
+        .arg("amount=50")
+        .arg("--param")
+        .arg("bonus=5")
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("scripted_effect.txt"),
+        )
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!("effect={\n\tadd_gold=50\n\tadd_prestige=5\n}\n", actual);
+}
+
+#[test]
+fn test_interpolate_unexpanded_params_warning() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("interpolate")
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("scripted_effect.txt"),
+        )
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let err = std::str::from_utf8(&out.stderr).unwrap();
+    assert!(err.contains("warning: Unexpanded parameter: $amount$\n"));
+    assert!(err.contains("scripted_effect.txt:2:13"));
+    assert!(err.contains("warning: Unexpanded parameter: [[bonus]\n"));
+    assert!(err.contains("warning: Unexpanded parameter: [[!bonus]\n"));
+}
+
+#[test]
+fn test_interpolate_inline_scripts() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("interpolate")
+        .arg("--mod-root")
+        .arg(&Path::new("tests").join("fixtures").join("inline_mod"))
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("inline_script.txt"),
+        )
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!(
+        "effect={\n\tadd_gold=50\n\tadd_prestige=5\n\tadd_piety=1\n}\n",
+        actual
+    );
+}
+
+#[test]
+fn test_interpolate_recursive_inline_scripts() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("interpolate")
+        .arg("--mod-root")
+        .arg(&Path::new("tests").join("fixtures").join("inline_mod"))
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("inline_script_loop.txt"),
+        )
+        .assert()
+        .failure();
+
+    let out = assert.get_output();
+    let err = std::str::from_utf8(&out.stderr).unwrap();
+    assert!(err.contains("Recursive inline script: loop/a -> loop/b -> loop/a"));
+}
+
+#[test]
+fn test_interpolate_windows1252() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("interpolate")
+        .arg(&Path::new("tests").join("fixtures").join("windows1252.txt"))
+        .assert()
+        .success();
+
+    // Text is written back in the encoding it was read in, and quoted text
+    // keeps its escapes as written
+    let out = assert.get_output();
+    let expected: &[u8] =
+        b"name=Z\xfcrich\ntitle=\"say \\\"hi\\\" in Z\xfcrich\"\nlabel=\"Z\xfcrich\"\n";
+    assert_eq!(expected, out.stdout.as_slice());
+}
+
+#[test]
+fn test_interpolate_inline_script_error_locations() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("interpolate")
+        .arg("--mod-root")
+        .arg(&Path::new("tests").join("fixtures").join("inline_mod"))
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("inline_script_errors.txt"),
+        )
+        .assert()
+        .failure();
+
+    let out = assert.get_output();
+    let err = std::str::from_utf8(&out.stderr).unwrap().replace('\\', "/");
+    assert!(err.contains(
+        "--> tests/fixtures/inline_mod/common/inline_scripts/effects/divide.txt:1:12\n  |\n1 | add_gold = @[10 / 0]\n"
+    ));
+    assert!(err.contains(
+        "--> tests/fixtures/inline_script_errors.txt:8:14\n  |\n8 | \tadd_piety = @[1 / 0]\n"
+    ));
+}
@@ -1,6 +1,7 @@
-use std::path::Path;
+mod utils;
 
 use assert_cmd::Command;
+use std::path::Path;
 
 #[test]
 fn test_json() {
@@ -94,3 +95,354 @@ fn test_json_windows1252() {
     let actual = std::str::from_utf8(&out.stdout).unwrap();
     assert_eq!(r#"{"a":"b","a":1}"#, actual);
 }
+
+#[test]
+fn test_json_output_format_yaml() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg(&Path::new("tests").join("fixtures").join("json.txt"))
+        .arg("--duplicate-keys")
+        .arg("group")
+        .arg("--output-format")
+        .arg("yaml")
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!("a:\n- b\n- 1\n", actual);
+}
+
+#[test]
+fn test_json_output_format_yaml_duplicate_keys() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg(&Path::new("tests").join("fixtures").join("json.txt"))
+        .arg("--output-format")
+        .arg("yaml")
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual: serde_yaml::Value = serde_yaml::from_slice(&out.stdout).unwrap();
+    let expected: serde_yaml::Value = serde_yaml::from_str("a: [b, 1]").unwrap();
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn test_json_output_format_toml_duplicate_keys() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    cmd.arg("json")
+        .arg(&Path::new("tests").join("fixtures").join("json.txt"))
+        .arg("--output-format")
+        .arg("toml")
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_json_detect_utf8_bom() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg(&Path::new("tests").join("fixtures").join("bom.txt"))
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!(r#"{"name":"Zürich"}"#, actual);
+}
+
+#[test]
+fn test_json_directory() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg(&Path::new("tests").join("fixtures").join("mod"))
+        .assert()
+        .code(1);
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!(
+        r#"{"common/b.txt":{"d":1},"common/names/a.txt":{"a":"b","c":[1,2]}}"#,
+        actual
+    );
+
+    let err = std::str::from_utf8(&out.stderr).unwrap();
+    assert!(err.starts_with("common/broken.txt: "));
+}
+
+#[test]
+fn test_json_directory_ndjson() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg("--ndjson")
+        .arg(&Path::new("tests").join("fixtures").join("mod"))
+        .assert()
+        .code(1);
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    let expected = concat!(
+        r#"{"path":"common/b.txt","data":{"d":1}}"#,
+        "\n",
+        r#"{"path":"common/names/a.txt","data":{"a":"b","c":[1,2]}}"#,
+        "\n"
+    );
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn test_json_eu4_with_meta() {
+    let file = utils::request("eu4saves-test-cases", "kandy2.bin.eu4");
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg("--with-meta")
+        .arg(&file)
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let json: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    let sections: Vec<_> = json.as_object().unwrap().keys().collect();
+    assert_eq!(sections, vec!["ai", "gamestate", "meta"]);
+    assert!(json["meta"]["date"].is_string());
+}
+
+#[test]
+fn test_json_ck3_with_meta() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg("--with-meta")
+        .arg(&Path::new("tests").join("fixtures").join("save.ck3"))
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let json: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    let sections: Vec<_> = json.as_object().unwrap().keys().collect();
+    assert_eq!(sections, vec!["gamestate", "header", "meta"]);
+    assert_eq!(
+        json["header"],
+        serde_json::json!({
+            "version": 1,
+            "kind": "text",
+            "binary": false,
+            "compressed": false,
+            "meta_length": 53,
+        })
+    );
+    assert_eq!(json["meta"]["meta_data"]["version"], "1.12.4");
+    assert_eq!(json["gamestate"]["date"], "1066.9.15");
+    assert_eq!(json["gamestate"]["played_character"]["character"], 6878);
+}
+
+#[test]
+fn test_json_localization() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg("--strip-formatting")
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("localization_l_english.yml"),
+        )
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!(
+        r#"{"l_english":{"GREETING":{"version":0,"text":"Hello $NAME$"},"FAREWELL":{"version":null,"text":"[Root.GetName] says \"bye\""}}}"#,
+        actual
+    );
+}
+
+#[test]
+fn test_json_canonical() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg("--canonical")
+        .arg(&Path::new("tests").join("fixtures").join("canonical.txt"))
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    let expected = "{\n  \"2\": true,\n  \"10\": {\n    \"x\": 1,\n    \"y\": 1.5\n  },\n  \"a\": 1,\n  \"b\": 2\n}\n";
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn test_json_group_by_path() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg("--group")
+        .arg("**.modifier")
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("duplicate_keys.txt"),
+        )
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!(
+        r#"{"a":1,"a":2,"country":{"modifier":["x","y"],"b":1}}"#,
+        actual
+    );
+}
+
+#[test]
+fn test_json_duplicate_key_rules_file() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg("--duplicate-key-rules")
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("duplicate_keys.rules"),
+        )
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("duplicate_keys.txt"),
+        )
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!(
+        r#"{"a":[1,2],"country":{"modifier":"x","b":1,"modifier":"y"}}"#,
+        actual
+    );
+}
+
+#[test]
+fn test_json_preserve_operators() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg("--operators")
+        .arg("preserve")
+        .arg(&Path::new("tests").join("fixtures").join("operators.txt"))
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!(
+        r#"{"a":{"op":">=","value":5},"b":{"op":"?=","value":true},"c":1,"d":{"op":"==","value":2}}"#,
+        actual
+    );
+}
+
+#[test]
+fn test_json_flatten() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg("--flatten")
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("duplicate_keys.txt"),
+        )
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    let expected =
+        "a = 1\na#2 = 2\ncountry.modifier = \"x\"\ncountry.b = 1\ncountry.modifier#2 = \"y\"\n";
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn test_json_vars_from() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg("--interpolation")
+        .arg("--vars-from")
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("scripted_variables"),
+        )
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("interpolation.txt"),
+        )
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!(r#"{"position":{"x":25,"y":40}}"#, actual);
+
+    let err = std::str::from_utf8(&out.stderr).unwrap();
+    assert!(err.contains("warning: @scale in "));
+}
+
+#[test]
+fn test_json_interpolation_error_locations() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg("--interpolation")
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("interpolation_errors.txt"),
+        )
+        .assert()
+        .failure();
+
+    let out = assert.get_output();
+    let err = std::str::from_utf8(&out.stderr).unwrap();
+    assert!(err.contains("Unknown operand: missing"));
+    assert!(err.contains("interpolation_errors.txt:2:8"));
+    assert!(err.contains("2 | cost = @[base * missing]\n  |        ^^^^^^^^^^^^^^^^^"));
+    assert!(err.contains("Division by zero"));
+    assert!(err.contains("interpolation_errors.txt:3:10"));
+}
+
+#[test]
+fn test_json_inline_scripts() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg("--interpolation")
+        .arg("--mod-root")
+        .arg(&Path::new("tests").join("fixtures").join("inline_mod"))
+        .arg(
+            &Path::new("tests")
+                .join("fixtures")
+                .join("inline_script.txt"),
+        )
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!(
+        r#"{"effect":{"add_gold":50,"add_prestige":5,"add_piety":1}}"#,
+        actual
+    );
+}
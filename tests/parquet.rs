@@ -0,0 +1,92 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use arrow::{
+    array::{Array, Date32Array, Float64Array, Int64Array, StringArray},
+    datatypes::DataType,
+    ipc::reader::FileReader,
+    record_batch::RecordBatch,
+};
+use assert_cmd::Command;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+fn export(extension: &str) -> PathBuf {
+    let out = std::env::temp_dir().join(format!(
+        "rakaly-parquet-{}.{}",
+        std::process::id(),
+        extension
+    ));
+
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    cmd.arg("parquet")
+        .arg("--path")
+        .arg("provinces")
+        .arg("-o")
+        .arg(&out)
+        .arg(&Path::new("tests").join("fixtures").join("parquet.txt"))
+        .assert()
+        .success();
+    out
+}
+
+fn assert_provinces(batch: &RecordBatch) {
+    let schema = batch.schema();
+    let names: Vec<_> = schema.fields().iter().map(|x| x.name().as_str()).collect();
+    assert_eq!(
+        names,
+        vec!["_key", "id", "name", "base_tax", "founded", "version", "cores"]
+    );
+    assert!(matches!(
+        schema.field_with_name("cores").unwrap().data_type(),
+        DataType::List(_)
+    ));
+
+    let column = |name: &str| batch.column_by_name(name).unwrap().clone();
+    let keys = column("_key");
+    let keys = keys.as_any().downcast_ref::<StringArray>().unwrap();
+    assert_eq!((keys.value(0), keys.value(1)), ("-1", "-2"));
+
+    let ids = column("id");
+    let ids = ids.as_any().downcast_ref::<Int64Array>().unwrap();
+    assert_eq!((ids.value(0), ids.value(1)), (100, 101));
+
+    let taxes = column("base_tax");
+    let taxes = taxes.as_any().downcast_ref::<Float64Array>().unwrap();
+    assert_eq!((taxes.value(0), taxes.value(1)), (5.0, 3.5));
+
+    let founded = column("founded");
+    let founded = founded.as_any().downcast_ref::<Date32Array>().unwrap();
+    assert_eq!(founded.value(0), -191803);
+
+    let versions = column("version");
+    let versions = versions.as_any().downcast_ref::<StringArray>().unwrap();
+    assert_eq!((versions.value(0), versions.value(1)), ("1.3.5", "1.37.2"));
+    assert_eq!(versions.null_count(), 0);
+}
+
+#[test]
+fn test_parquet_read_back() {
+    let out = export("parquet");
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&out).unwrap())
+        .unwrap()
+        .build()
+        .unwrap();
+    let batches: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+    assert_eq!(batches.len(), 1);
+    assert_eq!(batches[0].num_rows(), 2);
+    assert_provinces(&batches[0]);
+    std::fs::remove_file(&out).unwrap();
+}
+
+#[test]
+fn test_arrow_ipc_read_back() {
+    let out = export("arrow");
+    let reader = FileReader::try_new(File::open(&out).unwrap(), None).unwrap();
+    let batches: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+    assert_eq!(batches.len(), 1);
+    assert_eq!(batches[0].num_rows(), 2);
+    assert_provinces(&batches[0]);
+    std::fs::remove_file(&out).unwrap();
+}
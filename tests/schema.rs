@@ -0,0 +1,107 @@
+use std::path::Path;
+
+use assert_cmd::Command;
+use serde_json::{json, Value};
+
+fn rakaly(args: &[&str], file: &str) -> Value {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .args(args)
+        .arg(&Path::new("tests").join("fixtures").join(file))
+        .assert()
+        .success();
+
+    serde_json::from_slice(&assert.get_output().stdout).unwrap()
+}
+
+/// Validate a document against the subset of JSON Schema that the schema
+/// command writes
+fn validate(schema: &Value, value: &Value) -> bool {
+    if let Some(types) = schema.get("type") {
+        let types: Vec<_> = match types {
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            types => types.as_str().into_iter().collect(),
+        };
+
+        let matches = types.iter().any(|kind| match (*kind, value) {
+            ("null", Value::Null) => true,
+            ("boolean", Value::Bool(_)) => true,
+            ("number", Value::Number(_)) => true,
+            ("integer", Value::Number(x)) => x.is_i64() || x.is_u64(),
+            ("string", Value::String(_)) => true,
+            ("array", Value::Array(_)) => true,
+            ("object", Value::Object(_)) => true,
+            _ => false,
+        });
+        if !matches {
+            return false;
+        }
+    }
+
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        if !values.contains(value) {
+            return false;
+        }
+    }
+
+    match value {
+        Value::Array(values) => match schema.get("items") {
+            Some(items) => values.iter().all(|x| validate(items, x)),
+            None => true,
+        },
+        Value::Object(entries) => {
+            let required = schema
+                .get("required")
+                .and_then(Value::as_array)
+                .map_or(&[][..], |x| x.as_slice());
+            let present = required
+                .iter()
+                .filter_map(Value::as_str)
+                .all(|key| entries.contains_key(key));
+
+            present
+                && entries.iter().all(|(key, value)| {
+                    match (
+                        schema.get("properties").and_then(|x| x.get(key)),
+                        schema.get("additionalProperties"),
+                    ) {
+                        (Some(property), _) | (None, Some(property)) => validate(property, value),
+                        (None, None) => true,
+                    }
+                })
+        }
+        _ => true,
+    }
+}
+
+#[test]
+fn test_schema() {
+    let schema = rakaly(&["schema"], "json.txt");
+    let expected = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "type": "object",
+        "required": ["a"],
+        "properties": {
+            "a": {
+                "type": ["integer", "string"],
+                "x-duplicate-key": true,
+                "x-max-occurrences": 2
+            }
+        }
+    });
+    assert_eq!(expected, schema);
+}
+
+#[test]
+fn test_schema_validates_its_fixtures() {
+    // The schema describes documents with duplicate keys preserved
+    for file in ["json.txt", "table.txt", "parquet.txt"] {
+        let schema = rakaly(&["schema"], file);
+        let document = rakaly(&["json"], file);
+        assert!(
+            validate(&schema, &document),
+            "{} doesn't match its schema",
+            file
+        );
+    }
+}
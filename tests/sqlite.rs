@@ -0,0 +1,121 @@
+use std::path::Path;
+
+use assert_cmd::Command;
+use rusqlite::Connection;
+
+#[test]
+fn test_sqlite_export() {
+    let out = std::env::temp_dir().join(format!("rakaly-sqlite-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&out);
+
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    cmd.arg("sqlite")
+        .arg("--out")
+        .arg(&out)
+        .arg(&Path::new("tests").join("fixtures").join("sqlite.txt"))
+        .assert()
+        .success();
+
+    let conn = Connection::open(&out).unwrap();
+    let date: String = conn
+        .query_row("SELECT date FROM saves", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(date, "1444.11.11");
+
+    // Keys that only differ by case are written to the same column
+    let provinces: Vec<(String, String, String)> = conn
+        .prepare("SELECT _key, name, owner FROM provinces ORDER BY _id")
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        provinces,
+        vec![
+            (
+                String::from("-1"),
+                String::from("Stockholm"),
+                String::from("SWE")
+            ),
+            (
+                String::from("-2"),
+                String::from("Den Haag"),
+                String::from("HOL")
+            ),
+        ]
+    );
+
+    let speed: i64 = conn
+        .query_row(
+            "SELECT value FROM entries WHERE path = 'speed'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(speed, 2);
+    drop(conn);
+
+    // The same save can't be exported twice
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    cmd.arg("sqlite")
+        .arg("--out")
+        .arg(&out)
+        .arg(&Path::new("tests").join("fixtures").join("sqlite.txt"))
+        .assert()
+        .failure();
+
+    std::fs::remove_file(&out).unwrap();
+}
+
+#[test]
+fn test_sqlite_append() {
+    let out = std::env::temp_dir().join(format!("rakaly-sqlite-append-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&out);
+
+    for file in ["sqlite.txt", "sqlite_1445.txt"] {
+        let mut cmd = Command::cargo_bin("rakaly").unwrap();
+        cmd.arg("sqlite")
+            .arg("--out")
+            .arg(&out)
+            .arg(&Path::new("tests").join("fixtures").join(file))
+            .assert()
+            .success();
+    }
+
+    let conn = Connection::open(&out).unwrap();
+    let owners: Vec<(String, String, Option<String>)> = conn
+        .prepare(
+            "SELECT s.date, p.owner, p.__key FROM provinces p JOIN saves s ON p._save_id = s._id WHERE p._key = '-1' ORDER BY s.date",
+        )
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        owners,
+        vec![
+            (String::from("1444.11.11"), String::from("SWE"), None),
+            (
+                String::from("1445.1.1"),
+                String::from("DAN"),
+                Some(String::from("oops"))
+            ),
+        ]
+    );
+
+    let speeds: Vec<i64> = conn
+        .prepare(
+            "SELECT e.value FROM entries e JOIN saves s ON e._save_id = s._id WHERE e.path = 'speed' ORDER BY s.date",
+        )
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(speeds, vec![2, 3]);
+    drop(conn);
+
+    std::fs::remove_file(&out).unwrap();
+}
@@ -0,0 +1,58 @@
+use std::path::Path;
+
+use assert_cmd::Command;
+
+#[test]
+fn test_table_csv() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("table")
+        .arg("--path")
+        .arg("provinces")
+        .arg("--columns")
+        .arg("name,owner,base_tax,history.owner")
+        .arg(&Path::new("tests").join("fixtures").join("table.txt"))
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    let expected =
+        "_key,name,owner,base_tax,history.owner\n-1,Stockholm,SWE,5,SWE\n-2,Den Haag,,3.5,HOL\n";
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn test_table_tsv_default_columns() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("table")
+        .arg("--path")
+        .arg("provinces")
+        .arg("--tsv")
+        .arg(&Path::new("tests").join("fixtures").join("table.txt"))
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    let expected = "_key\tname\towner\tbase_tax\n-1\tStockholm\tSWE\t5\n-2\tDen Haag\t\t3.5\n";
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn test_table_entity_id_field() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("table")
+        .arg("--path")
+        .arg("characters")
+        .arg(&Path::new("tests").join("fixtures").join("table_ids.txt"))
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    let expected = "_key,id,name\n1,100,Erik\n2,200,Karl\n";
+    assert_eq!(expected, actual);
+}
@@ -0,0 +1,64 @@
+use std::path::Path;
+
+use assert_cmd::Command;
+
+#[test]
+fn test_text_round_trip() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg(&Path::new("tests").join("fixtures").join("json.txt"))
+        .assert()
+        .success();
+    let json = assert.get_output().stdout.clone();
+
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd.arg("text").write_stdin(json).assert().success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!("a=b\na=1\n", actual);
+}
+
+#[test]
+fn test_text_duplicate_key_group() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("text")
+        .arg("--duplicate-keys")
+        .arg("group")
+        .write_stdin(r#"{"a":[{"b":1},{"b":2}]}"#)
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!("a={\n\tb=1\n}\na={\n\tb=2\n}\n", actual);
+}
+
+#[test]
+fn test_text_duplicate_key_group_round_trip() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("json")
+        .arg("--duplicate-keys")
+        .arg("group")
+        .arg(&Path::new("tests").join("fixtures").join("json.txt"))
+        .assert()
+        .success();
+    let json = assert.get_output().stdout.clone();
+    assert_eq!(r#"{"a":["b",1]}"#, std::str::from_utf8(&json).unwrap());
+
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("text")
+        .arg("--duplicate-keys")
+        .arg("group")
+        .write_stdin(json)
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    assert_eq!("a=b\na=1\n", actual);
+}
@@ -0,0 +1,63 @@
+use assert_cmd::Command;
+use std::path::Path;
+
+#[test]
+fn test_vars() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("vars")
+        .arg(&Path::new("tests").join("fixtures").join("vars.txt"))
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let actual = std::str::from_utf8(&out.stdout).unwrap();
+    let expected = r#"tests/fixtures/vars.txt:1:1: @scale = 10 (used 2 times)
+tests/fixtures/vars.txt:2:1: @double = 20 from @[scale * 2] (used 1 time)
+tests/fixtures/vars.txt:3:1: @spare = 5 (unused)
+tests/fixtures/vars.txt:4:1: @name = "Grand Army" (used 1 time)
+tests/fixtures/vars.txt:6:2: @inner = 3 (used 1 time)
+tests/fixtures/vars.txt:11:8: @missing is undefined
+"#;
+    assert_eq!(expected, actual.replace('\\', "/"));
+}
+
+#[test]
+fn test_vars_json() {
+    let mut cmd = Command::cargo_bin("rakaly").unwrap();
+    let assert = cmd
+        .arg("vars")
+        .arg("--json")
+        .arg(&Path::new("tests").join("fixtures").join("vars.txt"))
+        .assert()
+        .success();
+
+    let out = assert.get_output();
+    let json: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    let file = Path::new("tests")
+        .join("fixtures")
+        .join("vars.txt")
+        .display()
+        .to_string();
+    assert_eq!(
+        json["variables"][1],
+        serde_json::json!({
+            "name": "double",
+            "value": 20,
+            "definition": "@[scale * 2]",
+            "file": file,
+            "line": 2,
+            "column": 1,
+            "root": true,
+            "uses": 1,
+            "unused": false,
+        })
+    );
+    assert_eq!(json["variables"][2]["unused"], true);
+    assert_eq!(json["variables"][4]["root"], false);
+    assert_eq!(
+        json["undefined"],
+        serde_json::json!([{"name": "missing", "file": file, "line": 11, "column": 8}])
+    );
+    assert_eq!(json["errors"], serde_json::json!([]));
+}